@@ -24,9 +24,7 @@ async fn main() {
 
     // Remove local socket from the sockets pool.
     for arg in args {
-        let current_socket = arg.parse::<SocketAddrV4>();
-        if current_socket.is_ok() {
-            let current_socket = current_socket.unwrap();
+        if let Ok(current_socket) = arg.parse::<SocketAddrV4>() {
             if sockets.contains(&current_socket) {
                 sockets.remove(&current_socket);
                 local_socket = Some(current_socket);
@@ -44,12 +42,16 @@ async fn main() {
     let service = rpc::Service::new(
         SocketAddr::from(local_socket),
         rpc::PingRequest::new("Ping".to_string()),
-        rpc::PingResponse::new("Pong".to_string()),
     );
+    let handler = |_: rpc::PingRequest, _| async {
+        rpc::PingResponse::new("Pong".to_string())
+    };
     let listen_service = service.clone();
 
     let task =
-        tokio::spawn(async move { listen_service.handle_request().await });
+        tokio::spawn(
+            async move { listen_service.handle_request(handler).await },
+        );
 
     let slp = 2;
     log::info!("Sleeping for {} seconds to wait for peers to start", slp);
@@ -0,0 +1,409 @@
+//! Configuration of a Raft node.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::timer::ElectionTimer;
+
+/// Timing, election, replication and snapshot configuration of a Raft
+/// node.
+///
+/// ``` rust
+/// use raft::config::Config;
+///
+/// let config = Config::new()
+///     .set_election_timeout(500..=1000)
+///     .set_heartbeat_interval(100);
+/// assert!(config.validate().is_ok());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Range of the random election timeout in milliseconds.
+    election_timeout: RangeInclusive<u16>,
+
+    /// Interval between heartbeats of the leader in milliseconds.
+    heartbeat_interval: u16,
+
+    /// Whether a Pre-Vote round is held before starting an election.
+    pre_vote: bool,
+
+    /// Whether the leader steps down when it has not heard from a
+    /// majority of the cluster within an election timeout.
+    check_quorum: bool,
+
+    /// How the leader serves linearizable reads.
+    read_mode: ReadMode,
+
+    /// Number of entries applied since the last snapshot that triggers
+    /// a new one, `0` to only take snapshots manually.
+    snapshot_threshold: u64,
+
+    /// Maximum size in bytes of a snapshot chunk sent to a follower.
+    snapshot_chunk_size: u32,
+
+    /// Maximum number of entries in a single AppendEntries.
+    max_batch_size: u32,
+
+    /// Maximum number of AppendEntries carrying entries sent to a
+    /// follower and not acknowledged yet.
+    max_inflight: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    /// Default interval between heartbeats in milliseconds.
+    pub const DEFAULT_HEARTBEAT_INTERVAL: u16 = 50;
+
+    /// Minimum number of heartbeats sent within the shortest election
+    /// timeout, so that a few lost heartbeats do not trigger an election.
+    pub const MIN_HEARTBEATS_PER_TIMEOUT: u16 = 3;
+
+    /// Default number of entries applied between snapshots.
+    pub const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 10_000;
+
+    /// Default maximum size of a snapshot chunk in bytes.
+    pub const DEFAULT_SNAPSHOT_CHUNK_SIZE: u32 = 64 * 1024;
+
+    /// Default maximum number of entries in a single AppendEntries.
+    pub const DEFAULT_MAX_BATCH_SIZE: u32 = 256;
+
+    /// Default maximum number of AppendEntries in flight per follower.
+    pub const DEFAULT_MAX_INFLIGHT: u32 = 8;
+
+    /// Create a configuration with an election timeout of 150 - 300 ms,
+    /// a heartbeat interval of 50 ms, neither Pre-Vote nor CheckQuorum,
+    /// safe reads, and a snapshot every 10,000 entries sent in chunks
+    /// of 64 KiB. Up to 8 AppendEntries of up to 256 entries each can be
+    /// in flight to a follower.
+    pub fn new() -> Config {
+        Config {
+            election_timeout: ElectionTimer::DEFAULT_RANGE,
+            heartbeat_interval: Self::DEFAULT_HEARTBEAT_INTERVAL,
+            pre_vote: false,
+            check_quorum: false,
+            read_mode: ReadMode::Safe,
+            snapshot_threshold: Self::DEFAULT_SNAPSHOT_THRESHOLD,
+            snapshot_chunk_size: Self::DEFAULT_SNAPSHOT_CHUNK_SIZE,
+            max_batch_size: Self::DEFAULT_MAX_BATCH_SIZE,
+            max_inflight: Self::DEFAULT_MAX_INFLIGHT,
+        }
+    }
+
+    /// Set the range of the random election timeout in milliseconds.
+    pub fn set_election_timeout(
+        mut self,
+        range: RangeInclusive<u16>,
+    ) -> Config {
+        self.election_timeout = range;
+        self
+    }
+
+    /// Set the interval between heartbeats of the leader in milliseconds.
+    pub fn set_heartbeat_interval(mut self, interval: u16) -> Config {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Enable or disable the Pre-Vote round.
+    ///
+    /// With Pre-Vote, a node only increments its term and starts an
+    /// election once a majority of the cluster agrees that it could win,
+    /// so a node coming back from a partition does not depose the
+    /// leader.
+    pub fn set_pre_vote(mut self, pre_vote: bool) -> Config {
+        self.pre_vote = pre_vote;
+        self
+    }
+
+    /// Enable or disable CheckQuorum.
+    ///
+    /// With CheckQuorum, a leader that has not heard from a majority of
+    /// the cluster within an election timeout steps down, instead of
+    /// accepting entries that can never be committed.
+    pub fn set_check_quorum(mut self, check_quorum: bool) -> Config {
+        self.check_quorum = check_quorum;
+        self
+    }
+
+    /// Set how the leader serves linearizable reads.
+    ///
+    /// See [`ReadMode`] for the trade-offs.
+    pub fn set_read_mode(mut self, read_mode: ReadMode) -> Config {
+        self.read_mode = read_mode;
+        self
+    }
+
+    /// Set the number of entries applied since the last snapshot
+    /// that triggers a new one, `0` to only take snapshots manually.
+    pub fn set_snapshot_threshold(mut self, threshold: u64) -> Config {
+        self.snapshot_threshold = threshold;
+        self
+    }
+
+    /// Set the maximum size in bytes of a snapshot chunk.
+    pub fn set_snapshot_chunk_size(mut self, size: u32) -> Config {
+        self.snapshot_chunk_size = size;
+        self
+    }
+
+    /// Set the maximum number of entries in a single AppendEntries.
+    ///
+    /// Entries proposed while the in-flight window of a follower is full
+    /// are sent together once an AppendEntries is acknowledged.
+    pub fn set_max_batch_size(mut self, size: u32) -> Config {
+        self.max_batch_size = size;
+        self
+    }
+
+    /// Set the maximum number of AppendEntries carrying entries sent to
+    /// a follower and not acknowledged yet.
+    ///
+    /// The leader sends new entries without waiting for the previous
+    /// ones to be acknowledged, as long as the follower accepted the
+    /// last ones. `1` waits for each AppendEntries to be acknowledged.
+    pub fn set_max_inflight(mut self, inflight: u32) -> Config {
+        self.max_inflight = inflight;
+        self
+    }
+
+    /// Get the range of the random election timeout in milliseconds.
+    pub fn election_timeout(&self) -> RangeInclusive<u16> {
+        self.election_timeout.clone()
+    }
+
+    /// Get the interval between heartbeats in milliseconds.
+    pub fn heartbeat_interval(&self) -> u16 {
+        self.heartbeat_interval
+    }
+
+    /// Check whether a Pre-Vote round is held before an election.
+    pub fn pre_vote(&self) -> bool {
+        self.pre_vote
+    }
+
+    /// Check whether the leader steps down without a majority.
+    pub fn check_quorum(&self) -> bool {
+        self.check_quorum
+    }
+
+    /// Get how the leader serves linearizable reads.
+    pub fn read_mode(&self) -> ReadMode {
+        self.read_mode
+    }
+
+    /// Get the number of entries applied between snapshots.
+    pub fn snapshot_threshold(&self) -> u64 {
+        self.snapshot_threshold
+    }
+
+    /// Get the maximum size in bytes of a snapshot chunk.
+    pub fn snapshot_chunk_size(&self) -> u32 {
+        self.snapshot_chunk_size
+    }
+
+    /// Get the maximum number of entries in a single AppendEntries.
+    pub fn max_batch_size(&self) -> u32 {
+        self.max_batch_size
+    }
+
+    /// Get the maximum number of AppendEntries in flight per follower.
+    pub fn max_inflight(&self) -> u32 {
+        self.max_inflight
+    }
+
+    /// Check that the configuration can keep a stable leader.
+    ///
+    /// The heartbeat interval must be well below the election timeout,
+    /// i.e. at least [`MIN_HEARTBEATS_PER_TIMEOUT`] heartbeats fit in the
+    /// shortest election timeout.
+    ///
+    /// [`MIN_HEARTBEATS_PER_TIMEOUT`]: #associatedconstant.MIN_HEARTBEATS_PER_TIMEOUT
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let (min, max) = self.election_timeout.clone().into_inner();
+        if min == 0 || min > max {
+            return Err(ConfigError::InvalidElectionTimeout(min, max));
+        }
+        let heartbeats = self.heartbeat_interval as u32
+            * Self::MIN_HEARTBEATS_PER_TIMEOUT as u32;
+        if self.heartbeat_interval == 0 || heartbeats > min as u32 {
+            return Err(ConfigError::InvalidHeartbeatInterval(
+                self.heartbeat_interval,
+            ));
+        }
+        if self.snapshot_chunk_size == 0 {
+            return Err(ConfigError::InvalidSnapshotChunkSize);
+        }
+        if self.max_batch_size == 0 {
+            return Err(ConfigError::InvalidMaxBatchSize);
+        }
+        if self.max_inflight == 0 {
+            return Err(ConfigError::InvalidMaxInflight);
+        }
+        if let ReadMode::Lease { clock_drift } = self.read_mode {
+            if clock_drift >= min {
+                return Err(ConfigError::InvalidClockDrift(clock_drift));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the leader serves linearizable reads with [`Node::read_index()`].
+///
+/// [`Node::read_index()`]: crate::node::Node::read_index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Every read waits for a heartbeat round acknowledged by a majority,
+    /// confirming that the node is still the leader.
+    ///
+    /// Safe whatever the clocks of the nodes.
+    #[default]
+    Safe,
+
+    /// Reads are served locally while the leader holds a lease, i.e.
+    /// a majority acknowledged a heartbeat sent less than the shortest
+    /// election timeout minus `clock_drift` milliseconds ago.
+    ///
+    /// Followers refuse to vote while the lease may run, so no other
+    /// leader can be elected. Only safe if the clocks of the nodes never
+    /// run apart by more than `clock_drift` in an election timeout.
+    Lease { clock_drift: u16 },
+}
+
+/// Errors of an invalid [`Config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The election timeout range, in milliseconds, is empty or zero.
+    InvalidElectionTimeout(u16, u16),
+
+    /// The heartbeat interval, in milliseconds, is zero or too close
+    /// to the election timeout.
+    InvalidHeartbeatInterval(u16),
+
+    /// The snapshot chunk size is zero.
+    InvalidSnapshotChunkSize,
+
+    /// The maximum number of entries in an AppendEntries is zero.
+    InvalidMaxBatchSize,
+
+    /// The maximum number of AppendEntries in flight is zero.
+    InvalidMaxInflight,
+
+    /// The clock drift margin of lease reads, in milliseconds,
+    /// leaves no time for a lease within the election timeout.
+    InvalidClockDrift(u16),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidElectionTimeout(min, max) => {
+                write!(f, "Invalid election timeout: {} - {} ms", min, max)
+            }
+            ConfigError::InvalidHeartbeatInterval(interval) => write!(
+                f,
+                "Heartbeat interval of {} ms must be non-zero and fit {} \
+                 times in the election timeout",
+                interval,
+                Config::MIN_HEARTBEATS_PER_TIMEOUT
+            ),
+            ConfigError::InvalidSnapshotChunkSize => {
+                write!(f, "Snapshot chunk size must be non-zero")
+            }
+            ConfigError::InvalidMaxBatchSize => {
+                write!(f, "Maximum batch size must be non-zero")
+            }
+            ConfigError::InvalidMaxInflight => {
+                write!(
+                    f,
+                    "Maximum number of messages in flight must be non-zero"
+                )
+            }
+            ConfigError::InvalidClockDrift(drift) => write!(
+                f,
+                "Invalid clock drift: {} ms, must be below the election \
+                 timeout",
+                drift
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let config = Config::new();
+        assert_eq!(config.election_timeout(), 150..=300);
+        assert_eq!(config.heartbeat_interval(), 50);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_election_timeout() {
+        let config = Config::new().set_election_timeout(0..=300);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidElectionTimeout(0, 300))
+        );
+
+        #[allow(clippy::reversed_empty_ranges)]
+        let config = Config::new().set_election_timeout(300..=150);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_heartbeat_interval() {
+        let config = Config::new().set_heartbeat_interval(0);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidHeartbeatInterval(0))
+        );
+
+        let config = Config::new().set_heartbeat_interval(51);
+        assert!(config.validate().is_err());
+
+        let config = Config::new()
+            .set_election_timeout(1000..=2000)
+            .set_heartbeat_interval(300);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_snapshot_chunk_size() {
+        let config = Config::new().set_snapshot_chunk_size(0);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidSnapshotChunkSize)
+        );
+    }
+
+    #[test]
+    fn test_invalid_replication_limits() {
+        let config = Config::new().set_max_batch_size(0);
+        assert_eq!(config.validate(), Err(ConfigError::InvalidMaxBatchSize));
+
+        let config = Config::new().set_max_inflight(0);
+        assert_eq!(config.validate(), Err(ConfigError::InvalidMaxInflight));
+    }
+
+    #[test]
+    fn test_invalid_clock_drift() {
+        let config =
+            Config::new().set_read_mode(ReadMode::Lease { clock_drift: 150 });
+        assert_eq!(config.validate(), Err(ConfigError::InvalidClockDrift(150)));
+
+        let config =
+            Config::new().set_read_mode(ReadMode::Lease { clock_drift: 20 });
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+//! Entries of the replicated log.
+
+use crate::membership::Membership;
+use crate::{Index, Term};
+
+/// A single entry in the replicated log.
+///
+/// Each entry contains a command for the state machine, and the term
+/// when the entry was received by the leader. Log indices start at `1`,
+/// index `0` with term `0` stands for the empty log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Term when the entry was received by the leader.
+    pub term: Term,
+
+    /// Position of the entry in the log.
+    pub index: Index,
+
+    /// What the entry holds.
+    pub kind: EntryKind,
+
+    /// Command for the state machine, opaque to Raft,
+    /// or encoded [`Membership`] for a membership change,
+    /// or nothing for a no-op.
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    /// Create a new entry holding a command for the state machine.
+    pub fn new(term: Term, index: Index, data: Vec<u8>) -> Self {
+        Entry { term, index, kind: EntryKind::Normal, data }
+    }
+
+    /// Create a new entry changing the membership of the cluster.
+    pub fn membership(
+        term: Term,
+        index: Index,
+        membership: &Membership,
+    ) -> Self {
+        Entry {
+            term,
+            index,
+            kind: EntryKind::Membership,
+            data: membership.encode(),
+        }
+    }
+
+    /// Create a new entry without any effect, appended by a new leader.
+    pub fn noop(term: Term, index: Index) -> Self {
+        Entry { term, index, kind: EntryKind::Noop, data: Vec::new() }
+    }
+}
+
+/// Kinds of log entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A command applied to the state machine.
+    #[default]
+    Normal,
+
+    /// A new membership of the cluster, taking effect as soon as the
+    /// entry is in the log, and never applied to the state machine.
+    Membership,
+
+    /// An empty entry appended by a leader when elected, so that it
+    /// commits an entry of its term without waiting for a command.
+    /// Never applied to the state machine.
+    Noop,
+}
+
+impl EntryKind {
+    /// Get the tag of the kind, as written on disk.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            EntryKind::Normal => 0,
+            EntryKind::Membership => 1,
+            EntryKind::Noop => 2,
+        }
+    }
+
+    /// Get the kind from a tag written on disk.
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(EntryKind::Normal),
+            1 => Some(EntryKind::Membership),
+            2 => Some(EntryKind::Noop),
+            _ => None,
+        }
+    }
+}
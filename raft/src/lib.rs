@@ -4,4 +4,22 @@
 //! https://www.usenix.org/system/files/conference/atc14/atc14-paper-ongaro.pdf)
 //! by **Diego Ongaro** and **John Ousterhout** for more details.
 
+pub mod config;
+pub mod entry;
+pub mod membership;
+pub mod message;
 pub mod node;
+pub mod proposal;
+pub mod read;
+pub mod session;
+pub mod snapshot;
+pub mod state_machine;
+pub mod storage;
+pub mod timer;
+
+/// Term number of the cluster.
+/// Initialized to `0` on first boot, and increases monotonically.
+pub type Term = u128;
+
+/// Index of an entry in the replicated log, starting at `1`.
+pub type Index = u64;
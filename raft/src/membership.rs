@@ -0,0 +1,257 @@
+//! Members of the cluster, changed with joint consensus.
+//!
+//! A membership change is a log entry, taking effect on every node as
+//! soon as the entry is in its log. To move from the old voters to the
+//! new ones without two majorities ever deciding apart, the leader first
+//! appends a joint membership, where every decision needs a majority of
+//! both the old and the new voters. Once it is committed, the leader
+//! appends the new membership alone.
+//!
+//! Learners are members that receive the log without voting, so adding
+//! or removing one takes a single entry. A new node joins as a learner,
+//! and is promoted to voter once it has caught up with the leader.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::storage::{decode_addr, encode_addr};
+use crate::Index;
+
+/// The members of the cluster.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Membership {
+    /// Voters of the membership, or of the new one during a change.
+    pub voters: BTreeSet<SocketAddr>,
+
+    /// Voters of the old membership during a change, empty otherwise.
+    pub outgoing: BTreeSet<SocketAddr>,
+
+    /// Members receiving the log but never counted in a quorum.
+    pub learners: BTreeSet<SocketAddr>,
+}
+
+impl Membership {
+    /// Create a membership with the given voters.
+    pub fn new<I>(voters: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        Membership {
+            voters: voters.into_iter().collect(),
+            outgoing: BTreeSet::new(),
+            learners: BTreeSet::new(),
+        }
+    }
+
+    /// Add learners to the membership.
+    pub fn set_learners<I>(mut self, learners: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        self.learners.extend(learners);
+        self
+    }
+
+    /// Check whether the membership is changing,
+    /// i.e. decisions need both the old and the new voters.
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Check whether the node is a voter of the old or the new membership.
+    pub fn is_voter(&self, addr: &SocketAddr) -> bool {
+        self.voters.contains(addr) || self.outgoing.contains(addr)
+    }
+
+    /// Check whether the node is a learner.
+    pub fn is_learner(&self, addr: &SocketAddr) -> bool {
+        self.learners.contains(addr)
+    }
+
+    /// Get all the members, old and new voters as well as learners.
+    pub fn members(&self) -> BTreeSet<SocketAddr> {
+        let voters = self.voters.union(&self.outgoing).copied();
+        voters.chain(self.learners.iter().copied()).collect()
+    }
+
+    /// Get the highest value reached by a majority of the voters, and
+    /// of the old voters as well during a change, e.g. the highest index
+    /// replicated on a majority given the match index of each voter.
+    pub fn quorum_index<F>(&self, value: F) -> Index
+    where
+        F: Fn(&SocketAddr) -> Index,
+    {
+        let quorum_index = |voters: &BTreeSet<SocketAddr>| {
+            let mut values: Vec<Index> = voters.iter().map(&value).collect();
+            values.sort_unstable_by(|a, b| b.cmp(a));
+            values.get(values.len() / 2).copied().unwrap_or(0)
+        };
+        let index = quorum_index(&self.voters);
+        if self.is_joint() {
+            index.min(quorum_index(&self.outgoing))
+        } else {
+            index
+        }
+    }
+
+    /// Check whether the given voters form a majority,
+    /// of both the old and the new voters during a change.
+    pub fn has_quorum<F>(&self, contains: F) -> bool
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        self.quorum_index(|addr| contains(addr) as Index) > 0
+    }
+
+    /// Encode the membership into bytes.
+    ///
+    /// The layout is `voters count (4) | voters | outgoing count (4) |
+    /// outgoing | learners count (4) | learners`, each address encoded
+    /// as in [`HardState::encode()`].
+    ///
+    /// [`HardState::encode()`]: crate::storage::HardState::encode
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for set in [&self.voters, &self.outgoing, &self.learners] {
+            data.extend_from_slice(&(set.len() as u32).to_le_bytes());
+            for addr in set {
+                encode_addr(addr, &mut data);
+            }
+        }
+        data
+    }
+
+    /// Decode the membership from bytes produced by [`encode()`].
+    ///
+    /// [`encode()`]: #method.encode
+    pub fn decode(mut data: &[u8]) -> io::Result<Self> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "Invalid membership");
+        let mut sets = [BTreeSet::new(), BTreeSet::new(), BTreeSet::new()];
+        for set in &mut sets {
+            let count = data.get(..4).ok_or_else(invalid)?;
+            let count = u32::from_le_bytes(count.try_into().unwrap());
+            data = &data[4..];
+            for _ in 0..count {
+                let (addr, size) = decode_addr(data).ok_or_else(invalid)?;
+                set.insert(addr);
+                data = &data[size..];
+            }
+        }
+        if !data.is_empty() {
+            return Err(invalid());
+        }
+        let [voters, outgoing, learners] = sets;
+        Ok(Membership { voters, outgoing, learners })
+    }
+}
+
+/// Reasons why the membership cannot be changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipError {
+    /// The node is not the leader. The leader of the current term is
+    /// given if known, so that the change can be requested there.
+    NotLeader { leader_hint: Option<SocketAddr> },
+
+    /// A previous change has not completed yet.
+    InProgress,
+
+    /// The new membership has no voters.
+    NoVoters,
+
+    /// The node to add as a learner is already a member.
+    AlreadyMember(SocketAddr),
+
+    /// The node to remove is not a learner.
+    NotLearner(SocketAddr),
+
+    /// The membership entry could not be written to the log.
+    Storage(io::ErrorKind),
+}
+
+impl fmt::Display for MembershipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MembershipError::NotLeader { leader_hint: Some(leader) } => {
+                write!(f, "Not the leader, try {}", leader)
+            }
+            MembershipError::NotLeader { leader_hint: None } => {
+                write!(f, "Not the leader")
+            }
+            MembershipError::InProgress => {
+                write!(f, "A membership change is already in progress")
+            }
+            MembershipError::NoVoters => {
+                write!(f, "The new membership has no voters")
+            }
+            MembershipError::AlreadyMember(addr) => {
+                write!(f, "{} is already a member", addr)
+            }
+            MembershipError::NotLearner(addr) => {
+                write!(f, "{} is not a learner", addr)
+            }
+            MembershipError::Storage(kind) => {
+                write!(f, "Failed to append the membership entry: {}", kind)
+            }
+        }
+    }
+}
+
+impl Error for MembershipError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_quorum_index() {
+        let membership = Membership::new((1..=3).map(addr));
+        let matched = [5, 3, 1];
+        let index = membership.quorum_index(|a| matched[a.port() as usize - 1]);
+        assert_eq!(index, 3);
+    }
+
+    #[test]
+    fn test_joint_quorum() {
+        let membership = Membership {
+            voters: (3..=5).map(addr).collect(),
+            outgoing: (1..=3).map(addr).collect(),
+            learners: [addr(6)].into(),
+        };
+        assert!(membership.is_joint());
+        assert_eq!(membership.members().len(), 6);
+
+        // A majority of the old voters is not enough, nor of the new ones.
+        assert!(!membership.has_quorum(|a| a.port() <= 3));
+        assert!(!membership.has_quorum(|a| a.port() >= 3));
+        assert!(membership.has_quorum(|a| [2, 3, 4].contains(&a.port())));
+    }
+
+    #[test]
+    fn test_learners_not_counted() {
+        let membership = Membership::new([addr(1)]).set_learners([addr(2)]);
+        assert!(membership.is_learner(&addr(2)));
+        assert!(!membership.is_voter(&addr(2)));
+        assert_eq!(membership.quorum_index(|a| a.port() as Index), 1);
+        assert!(!membership.has_quorum(|a| a.port() == 2));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let membership = Membership {
+            voters: [addr(1), "[::1]:2".parse().unwrap()].into(),
+            outgoing: [addr(3)].into(),
+            learners: [addr(4)].into(),
+        };
+        let data = membership.encode();
+        assert_eq!(Membership::decode(&data).unwrap(), membership);
+        assert!(Membership::decode(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Messages exchanged between Raft nodes.
+//!
+//! Every RPC in the Raft paper is modelled as a pair of plain structs,
+//! one for the arguments and one for the reply, both wrapped in
+//! [`Message`] so that a node can receive them from a single inbox.
+
+use std::net::SocketAddr;
+
+use crate::entry::Entry;
+use crate::membership::Membership;
+use crate::{Index, Term};
+
+/// Arguments of the RequestVote RPC.
+///
+/// Invoked by candidates to gather votes. Also sent by pre-candidates
+/// to check whether they could win an election, in which case `term`
+/// is the term the election would be held in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestVoteArgs {
+    /// Candidate's term.
+    pub term: Term,
+
+    /// Candidate requesting the vote.
+    pub candidate: SocketAddr,
+
+    /// Index of candidate's last log entry.
+    pub last_log_index: Index,
+
+    /// Term of candidate's last log entry.
+    pub last_log_term: Term,
+
+    /// `true` if the election was requested by the leader to hand over
+    /// its leadership, so voters must not refuse it for hearing from
+    /// that leader.
+    pub leadership_transfer: bool,
+}
+
+/// Reply of the RequestVote RPC.
+///
+/// Also used to answer a Pre-Vote request, in which case a granted vote
+/// carries the term of the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestVoteReply {
+    /// Current term of the voter, for candidate to update itself.
+    pub term: Term,
+
+    /// `true` means the candidate received the vote.
+    pub vote_granted: bool,
+}
+
+/// Arguments of the AppendEntries RPC.
+///
+/// Invoked by leader to replicate log entries, also used as heartbeat
+/// when `entries` is empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendEntriesArgs {
+    /// Leader's term.
+    pub term: Term,
+
+    /// So follower can redirect clients.
+    pub leader: SocketAddr,
+
+    /// Index of log entry immediately preceding new ones.
+    pub prev_log_index: Index,
+
+    /// Term of `prev_log_index` entry.
+    pub prev_log_term: Term,
+
+    /// Log entries to store, empty for heartbeat.
+    pub entries: Vec<Entry>,
+
+    /// Leader's commit index.
+    pub leader_commit: Index,
+
+    /// Heartbeat round of the leader, echoed in the reply so that the
+    /// leader can confirm it was still leading when it sent the message.
+    pub round: u64,
+}
+
+/// Reply of the AppendEntries RPC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendEntriesReply {
+    /// Current term of the follower, for leader to update itself.
+    pub term: Term,
+
+    /// `true` if follower contained entry matching
+    /// `prev_log_index` and `prev_log_term`.
+    pub success: bool,
+
+    /// Index of the last entry known to match the leader's log
+    /// on success, so that replies can be handled out of order.
+    pub match_index: Index,
+
+    /// Heartbeat round of the request being replied to.
+    pub round: u64,
+
+    /// On rejection, term of the conflicting entry at `prev_log_index`,
+    /// or `0` if the follower has no entry there.
+    pub conflict_term: Term,
+
+    /// On rejection, first index of `conflict_term` in the follower's
+    /// log, or the index after its last entry if `conflict_term` is `0`,
+    /// so that the leader can skip a whole term at once.
+    pub conflict_index: Index,
+}
+
+/// Arguments of the InstallSnapshot RPC.
+///
+/// Invoked by leader to send chunks of a snapshot to a follower
+/// lagging behind the compacted part of the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallSnapshotArgs {
+    /// Leader's term.
+    pub term: Term,
+
+    /// So follower can redirect clients.
+    pub leader: SocketAddr,
+
+    /// The snapshot replaces all entries up through and including
+    /// this index.
+    pub last_included_index: Index,
+
+    /// Term of `last_included_index`.
+    pub last_included_term: Term,
+
+    /// Membership of the cluster as of `last_included_index`.
+    pub membership: Membership,
+
+    /// Byte offset where chunk is positioned in the snapshot.
+    pub offset: u64,
+
+    /// Raw bytes of the snapshot chunk, starting at `offset`.
+    pub data: Vec<u8>,
+
+    /// `true` if this is the last chunk.
+    pub done: bool,
+}
+
+/// Reply of the InstallSnapshot RPC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallSnapshotReply {
+    /// Current term of the follower, for leader to update itself.
+    pub term: Term,
+
+    /// Last included index of the snapshot being received.
+    pub last_included_index: Index,
+
+    /// Byte offset of the chunk replied to.
+    pub chunk_offset: u64,
+
+    /// Byte offset of the next chunk expected by the follower.
+    pub offset: u64,
+
+    /// `true` once the whole snapshot is installed.
+    pub done: bool,
+}
+
+/// Arguments of the TimeoutNow RPC.
+///
+/// Invoked by leader to make an up-to-date follower start an election
+/// at once, handing over the leadership.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutNowArgs {
+    /// Leader's term.
+    pub term: Term,
+
+    /// Leader handing over its leadership.
+    pub leader: SocketAddr,
+}
+
+/// All messages that can be sent between Raft nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    PreVote(RequestVoteArgs),
+    PreVoteReply(RequestVoteReply),
+    RequestVote(RequestVoteArgs),
+    RequestVoteReply(RequestVoteReply),
+    AppendEntries(AppendEntriesArgs),
+    AppendEntriesReply(AppendEntriesReply),
+    InstallSnapshot(InstallSnapshotArgs),
+    InstallSnapshotReply(InstallSnapshotReply),
+    TimeoutNow(TimeoutNowArgs),
+}
+
+impl Message {
+    /// Get the term carried by the message.
+    pub fn term(&self) -> Term {
+        match self {
+            Message::PreVote(args) => args.term,
+            Message::PreVoteReply(reply) => reply.term,
+            Message::RequestVote(args) => args.term,
+            Message::RequestVoteReply(reply) => reply.term,
+            Message::AppendEntries(args) => args.term,
+            Message::AppendEntriesReply(reply) => reply.term,
+            Message::InstallSnapshot(args) => args.term,
+            Message::InstallSnapshotReply(reply) => reply.term,
+            Message::TimeoutNow(args) => args.term,
+        }
+    }
+}
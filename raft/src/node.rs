@@ -1,17 +1,124 @@
 //! In Raft, each server is represented as a node [`Node`].
 
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
 
-use rand::Rng;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ReadMode};
+use crate::entry::{Entry, EntryKind};
+use crate::membership::{Membership, MembershipError};
+use crate::message::{
+    AppendEntriesArgs, AppendEntriesReply, InstallSnapshotArgs,
+    InstallSnapshotReply, Message, RequestVoteArgs, RequestVoteReply,
+    TimeoutNowArgs,
+};
+use crate::proposal::{PendingProposal, ProposeError};
+use crate::read::{PendingRead, ReadError};
+use crate::snapshot::{Snapshot, SnapshotTransfer};
+use crate::state_machine::{Response, StateMachine};
+use crate::storage::{
+    HardState, HardStateFile, LogStorage, MemStorage, SnapshotFile, SyncPolicy,
+    WalStorage,
+};
+use crate::timer::{ElectionTimer, HeartbeatTimer};
+use crate::{Index, Term};
 
 /// A Raft node that represents a server in the cluster.
-pub struct Node {
+///
+/// The node itself does no I/O. Incoming messages are fed in with
+/// [`step()`], and messages to be sent to other nodes are queued until
+/// they are drained with [`take_messages()`].
+///
+/// Committed entries are applied to the [`StateMachine`] owned by the node.
+///
+/// A node is typically driven by a loop racing its election timer
+/// against incoming messages, without ever blocking the runtime:
+///
+/// ``` rust no_run
+/// # use std::net::SocketAddr;
+/// # use raft::{entry::Entry, message::Message, node::Node};
+/// # use raft::state_machine::StateMachine;
+/// # struct Noop;
+/// # impl StateMachine for Noop {
+/// #     fn apply(&mut self, _: &Entry) -> Vec<u8> { Vec::new() }
+/// #     fn snapshot(&self) -> Vec<u8> { Vec::new() }
+/// #     fn restore(&mut self, _: &[u8]) {}
+/// # }
+/// # async fn run(
+/// #     mut node: Node<Noop>,
+/// #     mut inbox: tokio::sync::mpsc::Receiver<(SocketAddr, Message)>,
+/// # ) {
+/// loop {
+///     tokio::select! {
+///         _ = node.timeout() => node.start_election(),
+///         _ = node.heartbeat() => node.send_heartbeat(),
+///         Some((from, message)) = inbox.recv() => node.step(from, message),
+///     }
+///     for (to, message) in node.take_messages() {
+///         // Send the message to `to`.
+///     }
+/// }
+/// # }
+/// ```
+///
+/// [`step()`]: #method.step
+/// [`take_messages()`]: #method.take_messages
+pub struct Node<S: StateMachine> {
     /// The current status of the node.
     status: Status,
 
     /// The current term of the node.
     /// Initialized to `0` on first boot, and increases monotonically.
-    current_term: u128,
+    current_term: Term,
+
+    /// Candidate that received vote in current term, if any.
+    voted_for: Option<SocketAddr>,
+
+    /// Log entries, kept by a pluggable storage backend.
+    log: Box<dyn LogStorage + Send>,
+
+    /// Index of highest log entry known to be committed.
+    /// Initialized to `0`, increases monotonically.
+    commit_index: Index,
+
+    /// Index of highest log entry applied to state machine.
+    /// Initialized to `0`, increases monotonically.
+    last_applied: Index,
+
+    /// The state machine that committed entries are applied to.
+    state_machine: S,
+
+    /// The leader of the current term, if known.
+    leader: Option<SocketAddr>,
+
+    /// When a leader was last heard from, used to refuse Pre-Votes
+    /// while it is still alive.
+    leader_contact: Option<Instant>,
+
+    /// Stable storage of the hard state,
+    /// `None` if the node only lives in memory.
+    hard_state_file: Option<HardStateFile>,
+
+    /// The hard state last written to stable storage.
+    persisted: HardState,
+
+    /// The latest snapshot, replacing the log entries it covers.
+    snapshot: Snapshot,
+
+    /// Stable storage of the latest snapshot,
+    /// `None` if the node only lives in memory.
+    snapshot_file: Option<SnapshotFile>,
+
+    /// Snapshot being received from the leader of the current term,
+    /// chunk by chunk.
+    incoming_snapshot: Option<Snapshot>,
 
     /// A socket address is the composition of
     ///
@@ -21,22 +128,232 @@ pub struct Node {
     /// [`SocketAddr`] is an enum of [`SocketAddr::V4`] and [`SocketAddr::V6`]
     socket_addr: SocketAddr,
 
-    /// Election timeout in milliseconds.
-    /// Typically 150 - 300 ms.
-    election_timeout: u16,
+    /// Voting members of the cluster, from the latest membership entry
+    /// in the log, even if not committed yet.
+    membership: Membership,
+
+    /// Index of the entry holding the membership, or of the snapshot
+    /// covering it. `0` for the initial membership.
+    membership_index: Index,
+
+    /// Socket addresses of all the other members of the cluster.
+    peers: Vec<SocketAddr>,
+
+    /// Votes received in the current term while being a candidate,
+    /// including the vote for itself.
+    votes: HashSet<SocketAddr>,
+
+    /// For each peer, index of the next log entry to send to that peer.
+    /// Only used by leader, reinitialized after election.
+    next_index: HashMap<SocketAddr, Index>,
+
+    /// For each peer, index of highest log entry known to be replicated
+    /// on that peer. Only used by leader, reinitialized after election.
+    match_index: HashMap<SocketAddr, Index>,
+
+    /// For each peer, index of the last entry of each AppendEntries
+    /// sent and not acknowledged yet, oldest first. Only used by leader.
+    inflight: HashMap<SocketAddr, VecDeque<Index>>,
+
+    /// Peers whose `next_index` is not confirmed yet, sent a single
+    /// AppendEntries at a time until one is accepted. Only used by leader.
+    probing: HashSet<SocketAddr>,
+
+    /// For each peer behind the compacted part of the log, the snapshot
+    /// being sent to that peer. Only used by leader.
+    snapshot_transfers: HashMap<SocketAddr, SnapshotTransfer>,
+
+    /// Messages waiting to be sent, paired with their destination.
+    messages: Vec<(SocketAddr, Message)>,
+
+    /// Timing and snapshot configuration of the node.
+    config: Config,
+
+    /// Election timer, reset on every valid heartbeat, cancelled while
+    /// being the leader unless CheckQuorum is enabled.
+    election_timer: ElectionTimer,
+
+    /// Heartbeat timer, only running while being the leader.
+    heartbeat_timer: HeartbeatTimer,
+
+    /// Peers heard from since the last election timeout.
+    /// Only used by leader with CheckQuorum enabled.
+    recent_active: HashSet<SocketAddr>,
+
+    /// Heartbeat round of the leader, incremented for every read
+    /// and increasing monotonically.
+    round: u64,
+
+    /// For each peer, highest heartbeat round acknowledged in the
+    /// current term. Only used by leader, reinitialized after election.
+    acked_round: HashMap<SocketAddr, u64>,
+
+    /// Heartbeat rounds not yet acknowledged by a majority, with the
+    /// time they were sent. Only used by leader in lease mode.
+    rounds: VecDeque<(u64, Instant)>,
+
+    /// End of the lease of the leader, if any.
+    /// Only used by leader in lease mode.
+    lease: Option<Instant>,
+
+    /// Peer the leadership is being handed over to, with the time after
+    /// which the transfer is abandoned. Only used by leader.
+    transfer: Option<(SocketAddr, Instant)>,
+
+    /// Reads waiting for the leadership to be confirmed,
+    /// or for the state machine to catch up, in request order.
+    pending_reads: VecDeque<PendingRead>,
+
+    /// Proposals waiting for their entry to be applied, in log order.
+    /// Only used by leader.
+    pending_proposals: VecDeque<PendingProposal>,
 }
 
-impl Node {
+impl<S: StateMachine> Node<S> {
     /// Create a new node with [`Status::Follower`] status.
-    pub fn new(socket_addr: SocketAddr) -> Self {
+    pub fn new(socket_addr: SocketAddr, state_machine: S) -> Self {
         log::debug!("Creating Node with IP: {}", socket_addr);
-        let mut rng = rand::thread_rng();
         Node {
             status: Status::Follower,
             current_term: 0,
+            voted_for: None,
+            log: Box::new(MemStorage::new()),
+            commit_index: 0,
+            last_applied: 0,
+            state_machine,
+            leader: None,
+            leader_contact: None,
+            hard_state_file: None,
+            persisted: HardState::default(),
+            snapshot: Snapshot {
+                membership: Membership::new([socket_addr]),
+                ..Snapshot::default()
+            },
+            snapshot_file: None,
+            incoming_snapshot: None,
             socket_addr,
-            election_timeout: rng.gen_range(150..=300),
+            membership: Membership::new([socket_addr]),
+            membership_index: 0,
+            peers: Vec::new(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            inflight: HashMap::new(),
+            probing: HashSet::new(),
+            snapshot_transfers: HashMap::new(),
+            messages: Vec::new(),
+            config: Config::new(),
+            election_timer: ElectionTimer::default(),
+            heartbeat_timer: HeartbeatTimer::new(
+                Config::DEFAULT_HEARTBEAT_INTERVAL,
+            ),
+            recent_active: HashSet::new(),
+            round: 0,
+            acked_round: HashMap::new(),
+            rounds: VecDeque::new(),
+            lease: None,
+            transfer: None,
+            pending_reads: VecDeque::new(),
+            pending_proposals: VecDeque::new(),
+        }
+    }
+
+    /// Open a node whose hard state, snapshot and log are kept in the
+    /// given directory, the log being a [`WalStorage`] synced on every
+    /// append.
+    ///
+    /// The term and vote saved before the last shutdown or crash are
+    /// restored, so the node never votes twice in the same term.
+    /// The state machine is restored from the latest snapshot, and the
+    /// committed entries that follow it are applied again.
+    pub fn open<P>(
+        socket_addr: SocketAddr,
+        state_machine: S,
+        dir: P,
+    ) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let log =
+            WalStorage::open(dir.as_ref().join("log"), SyncPolicy::Always)?;
+        Node::open_with_storage(socket_addr, state_machine, dir, log)
+    }
+
+    /// Open a node whose hard state and snapshot are kept in the given
+    /// directory, and whose log is kept by the given storage.
+    ///
+    /// See [`open()`] for details.
+    ///
+    /// [`open()`]: #method.open
+    pub fn open_with_storage<P, L>(
+        socket_addr: SocketAddr,
+        state_machine: S,
+        dir: P,
+        log: L,
+    ) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        L: LogStorage + Send + 'static,
+    {
+        let (hard_state_file, hard_state) = HardStateFile::open(&dir)?;
+        let (snapshot_file, snapshot) = SnapshotFile::open(&dir)?;
+        let mut node = Node::new(socket_addr, state_machine);
+        node.log = Box::new(log);
+        node.current_term = hard_state.term;
+        node.voted_for = hard_state.voted_for;
+        if let Some(snapshot) = snapshot {
+            node.install_snapshot(snapshot)?;
+        }
+        node.reload_membership();
+        // The commit index is saved separately from the log,
+        // so it must not point past the end of a log lost by the storage.
+        node.commit_index =
+            hard_state.commit.min(node.last_log_index()).max(node.commit_index);
+        node.apply_committed();
+        node.persisted = hard_state;
+        node.hard_state_file = Some(hard_state_file);
+        node.snapshot_file = Some(snapshot_file);
+        log::info!(
+            "Node: {} restored in term {}.",
+            node.socket_addr,
+            node.current_term
+        );
+        Ok(node)
+    }
+
+    /// Set the other nodes of the cluster, all of them voters along with
+    /// the node itself.
+    ///
+    /// The address of the node itself and duplicates are ignored.
+    /// See [`set_membership()`] for details.
+    ///
+    /// [`set_membership()`]: #method.set_membership
+    pub fn set_peers<I>(self, peers: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let voters = peers.into_iter().chain([self.socket_addr]);
+        self.set_membership(Membership::new(voters))
+    }
+
+    /// Set the initial membership of the cluster, by default the node
+    /// alone.
+    ///
+    /// Only used to bootstrap a new cluster. It is ignored once a
+    /// membership has been restored from the log or the snapshot, as
+    /// later changes go through the log with [`change_membership()`].
+    /// A node joining an existing cluster starts with an empty
+    /// membership, and never starts an election until the leader
+    /// replicates a membership where it is a voter, usually after adding
+    /// it as a learner.
+    ///
+    /// [`change_membership()`]: #method.change_membership
+    pub fn set_membership(mut self, membership: Membership) -> Self {
+        if self.membership_index == 0 {
+            self.snapshot.membership = membership.clone();
+            self.use_membership(membership, 0);
         }
+        self
     }
 
     /// Get the current status of the node.
@@ -45,87 +362,3587 @@ impl Node {
     }
 
     /// Get the current term of the node.
-    pub fn current_term(&self) -> u128 {
+    pub fn current_term(&self) -> Term {
         self.current_term
     }
 
+    /// Get the candidate voted for in the current term.
+    pub fn voted_for(&self) -> Option<SocketAddr> {
+        self.voted_for
+    }
+
+    /// Get the leader of the current term, if known.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.leader
+    }
+
+    /// Set the timing and snapshot configuration of the node,
+    /// after checking it with [`Config::validate()`].
+    pub fn set_config(mut self, config: Config) -> Result<Self, ConfigError> {
+        config.validate()?;
+        self.election_timer = ElectionTimer::new(config.election_timeout());
+        self.heartbeat_timer = HeartbeatTimer::new(config.heartbeat_interval());
+        self.config = config;
+        Ok(self)
+    }
+
+    /// Set the storage of the log entries, replacing the default
+    /// [`MemStorage`]. Must be called before the node starts.
+    pub fn set_log_storage<L>(mut self, log: L) -> Self
+    where
+        L: LogStorage + Send + 'static,
+    {
+        self.log = Box::new(log);
+        self
+    }
+
+    /// Get all the entries in the log not yet replaced by a snapshot.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.log.entries(self.log.first_index(), self.last_log_index() + 1)
+    }
+
+    /// Get the index of the last entry in the log, `0` if empty.
+    ///
+    /// Entries replaced by a snapshot still count as part of the log.
+    pub fn last_log_index(&self) -> Index {
+        self.log.last_index().max(self.snapshot.last_included_index)
+    }
+
+    /// Get the term of the last entry in the log, `0` if empty.
+    pub fn last_log_term(&self) -> Term {
+        self.term_at(self.last_log_index()).unwrap_or(0)
+    }
+
+    /// Get the index of highest log entry known to be committed.
+    pub fn commit_index(&self) -> Index {
+        self.commit_index
+    }
+
+    /// Get the index of highest log entry applied to state machine.
+    pub fn last_applied(&self) -> Index {
+        self.last_applied
+    }
+
+    /// Get the state machine of the node.
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Get the latest snapshot of the state machine.
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// Take a snapshot of the state machine covering all the applied
+    /// entries, then discard those entries from the log.
+    ///
+    /// Also done automatically every [`Config::snapshot_threshold()`]
+    /// applied entries.
+    pub fn take_snapshot(&mut self) -> io::Result<()> {
+        let index = self.last_applied;
+        if index <= self.snapshot.last_included_index {
+            return Ok(());
+        }
+        let snapshot = Snapshot {
+            last_included_index: index,
+            last_included_term: self.term_at(index).unwrap_or(0),
+            membership: self.membership_at(index).1,
+            data: self.state_machine.snapshot(),
+        };
+        // The snapshot must be on disk before the entries it replaces
+        // are deleted.
+        if let Some(file) = &mut self.snapshot_file {
+            file.save(&snapshot)?;
+        }
+        self.snapshot = snapshot;
+        self.log.compact(index)?;
+        log::info!(
+            "Node: {} took snapshot up to entry {}.",
+            self.socket_addr,
+            index
+        );
+        Ok(())
+    }
+
+    /// Get the state that must be kept on stable storage.
+    pub fn hard_state(&self) -> HardState {
+        HardState {
+            term: self.current_term,
+            voted_for: self.voted_for,
+            commit: self.commit_index,
+        }
+    }
+
     /// Get the IP address of the node.
     pub fn socket_addr(&self) -> &SocketAddr {
         &self.socket_addr
     }
 
+    /// Get the other members of the cluster.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Get the membership of the cluster, from the latest membership
+    /// entry in the log, even if not committed yet.
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
     /// Check if the node is a leader.
     pub fn is_leader(&self) -> bool {
         self.status.is_leader()
     }
 
-    pub fn timeout(&self) {
-        log::debug!(
-            "Initial election timeout for Node: {} in {} ms.",
+    /// Drain all the messages waiting to be sent.
+    pub fn take_messages(&mut self) -> Vec<(SocketAddr, Message)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Get the timing configuration of the node.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Get the election timer of the node.
+    pub fn election_timer(&self) -> &ElectionTimer {
+        &self.election_timer
+    }
+
+    /// Wait until the election timeout elapses,
+    /// which never happens while the node is the leader,
+    /// unless [`Config::check_quorum()`] is enabled.
+    ///
+    /// Does not block the runtime. The future can be dropped at any time,
+    /// and a later call picks up the timer as reset in the meantime.
+    pub async fn timeout(&self) {
+        log::trace!(
+            "Election timeout for Node: {} in {} ms.",
             self.socket_addr,
-            self.election_timeout
+            self.election_timer.timeout().as_millis()
         );
-        std::thread::sleep(std::time::Duration::from_millis(
-            self.election_timeout as u64,
-        ));
+        self.election_timer.elapsed().await;
         log::trace!("Node: {} timed out.", self.socket_addr);
     }
-}
 
-/// All possible status (states) of a Raft node.
-///
-/// As the word **State** have the same wording with *persistent* and
-/// *volatile state* in Node's data, we use **Status** instead.
-#[derive(Debug, PartialEq, Eq)]
-pub enum Status {
-    /// Follower status. The default status of a new node.
-    Follower,
+    /// Wait until the next heartbeat is due,
+    /// which only happens while the node is the leader.
+    ///
+    /// Like [`timeout()`], the future can be dropped at any time.
+    ///
+    /// [`timeout()`]: #method.timeout
+    pub async fn heartbeat(&self) {
+        self.heartbeat_timer.elapsed().await;
+    }
 
-    /// Candidate status.
-    Candidate,
+    /// Send a heartbeat to all the peers, and schedule the next one.
+    ///
+    /// Peers lagging behind receive the entries they are missing
+    /// instead of an empty AppendEntries.
+    pub fn send_heartbeat(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        log::trace!("Node: {} sent heartbeat.", self.socket_addr);
+        if self.transfer.is_some_and(|(_, end)| Instant::now() >= end) {
+            log::warn!(
+                "Node: {} abandoned leadership transfer.",
+                self.socket_addr
+            );
+            self.transfer = None;
+        }
+        self.next_round();
+        self.replicate();
+        self.heartbeat_timer.start();
+    }
 
-    /// Leader status.
-    Leader,
-}
+    /// Start a new election after the election timeout elapsed.
+    ///
+    /// The node increments its term, votes for itself
+    /// and sends RequestVote to all the peers.
+    /// A leader never starts an election.
+    ///
+    /// If [`Config::pre_vote()`] is enabled, the node first becomes
+    /// [`Status::PreCandidate`] and sends PreVote to all the peers,
+    /// keeping its term until a majority grants the Pre-Vote.
+    ///
+    /// If [`Config::check_quorum()`] is enabled, a leader that has not
+    /// heard from a majority of the cluster since the last timeout
+    /// steps down to [`Status::Follower`] instead.
+    pub fn start_election(&mut self) {
+        if self.is_leader() {
+            self.check_quorum();
+            return;
+        }
+        if !self.membership.is_voter(&self.socket_addr) {
+            log::debug!(
+                "Node: {} is not a voter, no election started.",
+                self.socket_addr
+            );
+            self.election_timer.reset();
+            return;
+        }
+        if self.config.pre_vote() {
+            self.become_pre_candidate();
+        } else {
+            self.become_candidate(false);
+        }
+        self.persist();
+    }
 
-impl Status {
-    pub fn is_leader(&self) -> bool {
-        matches!(self, Status::Leader)
+    /// Hand over the leadership to the given peer, e.g. before shutting
+    /// down the leader for maintenance.
+    ///
+    /// The leader stops accepting proposals, brings the log of the peer
+    /// up to date, then sends it TimeoutNow so that it starts an election
+    /// at once. The transfer is abandoned if the peer has not taken over
+    /// within the shortest election timeout, and proposals are accepted
+    /// again.
+    pub fn transfer_leadership(
+        &mut self,
+        target: SocketAddr,
+    ) -> Result<(), TransferError> {
+        if !self.is_leader() {
+            return Err(TransferError::NotLeader { leader_hint: self.leader });
+        }
+        if target == self.socket_addr {
+            return Ok(());
+        }
+        if !self.membership.voters.contains(&target) {
+            return Err(TransferError::UnknownTarget(target));
+        }
+        let timeout = *self.config.election_timeout().start();
+        let end = Instant::now() + Duration::from_millis(timeout.into());
+        self.transfer = Some((target, end));
+        // The target is elected without waiting for the lease to expire.
+        self.lease = None;
+        log::info!(
+            "Node: {} transferring leadership to {}.",
+            self.socket_addr,
+            target
+        );
+        if self.match_index.get(&target) == Some(&self.last_log_index()) {
+            self.send_timeout_now(target);
+        } else {
+            self.replicate_to(target);
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Get the peer the leadership is being handed over to, if any.
+    pub fn transferee(&self) -> Option<SocketAddr> {
+        self.transfer.map(|(target, _)| target)
+    }
 
-    static LOCAL_ADDR: &str = "127.0.0.0:2024";
+    /// Step down if a majority of the cluster, counting the leader
+    /// itself, has not been heard from since the last check.
+    fn check_quorum(&mut self) {
+        if !self.config.check_quorum() {
+            return;
+        }
+        let active = self.membership.has_quorum(|peer| {
+            *peer == self.socket_addr || self.recent_active.contains(peer)
+        });
+        self.recent_active.clear();
+        if active {
+            self.election_timer.reset();
+            return;
+        }
+        log::warn!(
+            "Node: {} lost contact with a majority in term {}, stepping down.",
+            self.socket_addr,
+            self.current_term
+        );
+        self.become_follower(self.current_term);
+        self.leader = None;
+    }
 
-    /// Make sure that a new node is created with:
-    /// - Status::Follower
-    /// - Term 0
-    #[test]
-    fn test_new() {
-        let node = Node::new(LOCAL_ADDR.parse().unwrap());
-        assert!(matches!(node.status(), Status::Follower));
-        assert_eq!(node.current_term(), 0);
+    /// Append a new entry with the given data to the log of the leader,
+    /// and start replicating it to the peers.
+    ///
+    /// The returned future resolves to the response of the state machine
+    /// once the entry is committed and applied, or fails if the node is
+    /// not the leader, is handing over its leadership, or loses it before
+    /// the entry is applied. The entry is appended at once, whether the
+    /// future is awaited or not, and the node must keep being driven for
+    /// the future to resolve.
+    ///
+    /// ``` rust no_run
+    /// # use raft::{node::Node, state_machine::StateMachine};
+    /// # async fn write<S: StateMachine>(node: &mut Node<S>) {
+    /// let applied = node.propose(b"x".to_vec());
+    /// // Keep stepping the node until the entry is applied.
+    /// let response = applied.await.unwrap();
+    /// # }
+    /// ```
+    pub fn propose(
+        &mut self,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<Response, ProposeError>> + 'static {
+        let (ready, receiver) = oneshot::channel();
+        match self.append_entry(data) {
+            Ok(index) => {
+                self.pending_proposals
+                    .push_back(PendingProposal { index, ready });
+                // A single node cluster commits on its own.
+                self.advance_commit_index();
+                self.advance_reads();
+                self.send_entries();
+                self.persist();
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+            }
+        }
+        async move { receiver.await.unwrap_or(Err(ProposeError::TermChanged)) }
     }
 
-    #[test]
-    fn test_socket() {
-        let node = Node::new(LOCAL_ADDR.parse().unwrap());
-        assert!(node.socket_addr().ip().is_loopback());
-        assert_eq!(node.socket_addr().port(), 2024);
+    /// Append a new entry with the given data to the log of the leader.
+    fn append_entry(&mut self, data: Vec<u8>) -> Result<Index, ProposeError> {
+        if !self.is_leader() {
+            return Err(ProposeError::NotLeader { leader_hint: self.leader });
+        }
+        if let Some((target, _)) = self.transfer {
+            return Err(ProposeError::NotLeader { leader_hint: Some(target) });
+        }
+        let index = self.last_log_index() + 1;
+        let entry = Entry::new(self.current_term, index, data);
+        if let Err(e) = self.log.append(&[entry]) {
+            log::error!(
+                "Node: {} failed to append entry {}: {}",
+                self.socket_addr,
+                index,
+                e
+            );
+            return Err(ProposeError::Storage(e.kind()));
+        }
+        log::trace!(
+            "Node: {} appended entry {} in term {}.",
+            self.socket_addr,
+            index,
+            self.current_term
+        );
+        Ok(index)
     }
 
-    #[test]
-    fn test_is_leader() {
-        let leader = Status::Leader;
-        assert!(leader.is_leader());
+    /// Request a linearizable read, without writing to the log.
+    ///
+    /// The commit index is recorded as the read index, and a heartbeat
+    /// round is sent to confirm that the node is still the leader.
+    /// The returned future resolves to the read index once a majority
+    /// has acknowledged the round and the state machine has applied all
+    /// the entries up to the read index. The state machine can then be
+    /// read with [`state_machine()`], as it reflects every write
+    /// completed before the read was requested.
+    ///
+    /// The node must keep being driven for the future to resolve. A new
+    /// leader only serves reads once it has committed an entry from its
+    /// own term, such as the no-op entry it appends when elected.
+    ///
+    /// With [`ReadMode::Lease`], a leader holding its lease skips the
+    /// heartbeat round, and the future is ready at once.
+    ///
+    /// ``` rust no_run
+    /// # use raft::{node::Node, state_machine::StateMachine};
+    /// # async fn read<S: StateMachine>(node: &mut Node<S>) {
+    /// let ready = node.read_index();
+    /// // Keep stepping the node until the read is ready.
+    /// ready.await.unwrap();
+    /// let state = node.state_machine();
+    /// # }
+    /// ```
+    ///
+    /// [`state_machine()`]: #method.state_machine
+    pub fn read_index(
+        &mut self,
+    ) -> impl Future<Output = Result<Index, ReadError>> + 'static {
+        let (ready, receiver) = oneshot::channel();
+        let committed = self.term_at(self.commit_index);
+        if self.is_leader()
+            && self.transfer.is_none()
+            && self.holds_lease()
+            && committed == Some(self.current_term)
+            && self.last_applied >= self.commit_index
+        {
+            let _ = ready.send(Ok(self.commit_index));
+        } else if self.is_leader() {
+            self.next_round();
+            self.pending_reads.push_back(PendingRead {
+                index: (committed == Some(self.current_term))
+                    .then_some(self.commit_index),
+                round: self.round,
+                ready,
+            });
+            self.replicate();
+            self.advance_reads();
+        } else {
+            let _ = ready
+                .send(Err(ReadError::NotLeader { leader_hint: self.leader }));
+        }
+        async move { receiver.await.unwrap_or(Err(ReadError::LeadershipLost)) }
+    }
 
-        let follower = Status::Follower;
-        assert!(!follower.is_leader());
+    /// Change the voters of the cluster, e.g. to replace failed nodes.
+    ///
+    /// The leader appends a joint membership of the old and new voters,
+    /// where elections and commitment need a majority of both. Once it
+    /// is committed, the leader appends the membership of the new voters
+    /// alone, and steps down if it is not one of them once that one is
+    /// committed. Only one change can be in progress at a time.
+    ///
+    /// Learners among the new voters are promoted, the other learners
+    /// stay learners. Removed voters leave the cluster.
+    ///
+    /// Return the index of the joint membership entry.
+    pub fn change_membership<I>(
+        &mut self,
+        voters: I,
+    ) -> Result<Index, MembershipError>
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        self.check_membership_change()?;
+        let voters: BTreeSet<SocketAddr> = voters.into_iter().collect();
+        if voters.is_empty() {
+            return Err(MembershipError::NoVoters);
+        }
+        let learners = &self.membership.learners - &voters;
+        let joint = Membership {
+            voters,
+            outgoing: self.membership.voters.clone(),
+            learners,
+        };
+        self.commit_membership(joint)
+    }
 
-        let candidate = Status::Candidate;
-        assert!(!candidate.is_leader());
+    /// Add a learner to the cluster, receiving the log without voting.
+    ///
+    /// A new node joins as a learner, then is promoted with
+    /// [`change_membership()`] once its [`match_index()`] has caught up.
+    /// It can also serve reads on its own, possibly stale, or up to date
+    /// after waiting to have applied an index given by
+    /// [`read_index()`] on the leader.
+    ///
+    /// Return the index of the membership entry.
+    ///
+    /// [`change_membership()`]: #method.change_membership
+    /// [`match_index()`]: #method.match_index
+    /// [`read_index()`]: #method.read_index
+    pub fn add_learner(
+        &mut self,
+        learner: SocketAddr,
+    ) -> Result<Index, MembershipError> {
+        self.check_membership_change()?;
+        if self.membership.members().contains(&learner) {
+            return Err(MembershipError::AlreadyMember(learner));
+        }
+        let membership = self.membership.clone().set_learners([learner]);
+        self.commit_membership(membership)
+    }
+
+    /// Remove a learner from the cluster.
+    ///
+    /// Return the index of the membership entry.
+    pub fn remove_learner(
+        &mut self,
+        learner: SocketAddr,
+    ) -> Result<Index, MembershipError> {
+        self.check_membership_change()?;
+        if !self.membership.is_learner(&learner) {
+            return Err(MembershipError::NotLearner(learner));
+        }
+        let mut membership = self.membership.clone();
+        membership.learners.remove(&learner);
+        self.commit_membership(membership)
+    }
+
+    /// Get the index of the last entry known to be replicated on a peer,
+    /// if the node is the leader and the peer a member.
+    pub fn match_index(&self, peer: &SocketAddr) -> Option<Index> {
+        self.match_index.get(peer).copied()
+    }
+
+    /// Send AppendEntries to all the peers, carrying the entries
+    /// each of them is missing, or nothing as a heartbeat.
+    ///
+    /// A heartbeat checks the entry before `next_index`, so a peer that
+    /// lost entries in flight rejects it, and the missing entries are
+    /// sent again.
+    pub fn replicate(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        for peer in self.peers.clone() {
+            self.replicate_to(peer);
+        }
+    }
+
+    /// Send the entries the peer is missing, or a heartbeat if none can
+    /// be sent.
+    fn replicate_to(&mut self, peer: SocketAddr) {
+        if !self.send_append_entries(peer) {
+            self.send_empty_append_entries(peer);
+        }
+    }
+
+    /// Send the new entries to all the peers, as far as their in-flight
+    /// window allows.
+    fn send_entries(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        for peer in self.peers.clone() {
+            self.send_append_entries(peer);
+        }
+    }
+
+    /// Handle a message received from another node.
+    ///
+    /// Any message carrying a higher term makes the node step down
+    /// to [`Status::Follower`] before the message is processed, except
+    /// a disruptive RequestVote, which is dropped.
+    pub fn step(&mut self, from: SocketAddr, message: Message) {
+        if let Message::RequestVote(args) = &message {
+            if self.is_disruptive(args) {
+                log::debug!(
+                    "Node: {} ignored vote request from {} for term {}, \
+                     the leader is still heard from.",
+                    self.socket_addr,
+                    from,
+                    args.term
+                );
+                return;
+            }
+        }
+
+        // A Pre-Vote, or a granted one, carries the term of an election
+        // that has not started yet.
+        let pre_vote = match &message {
+            Message::PreVote(_) => true,
+            Message::PreVoteReply(reply) => reply.vote_granted,
+            _ => false,
+        };
+        if message.term() > self.current_term && !pre_vote {
+            log::debug!(
+                "Node: {} received term {} from {}, newer than {}.",
+                self.socket_addr,
+                message.term(),
+                from,
+                self.current_term
+            );
+            self.become_follower(message.term());
+        }
+
+        match message {
+            Message::PreVote(args) => {
+                let reply = self.handle_pre_vote(args);
+                self.send(from, Message::PreVoteReply(reply));
+            }
+            Message::PreVoteReply(reply) => {
+                self.handle_pre_vote_reply(from, reply);
+            }
+            Message::RequestVote(args) => {
+                let reply = self.handle_request_vote(args);
+                self.send(from, Message::RequestVoteReply(reply));
+            }
+            Message::RequestVoteReply(reply) => {
+                self.handle_request_vote_reply(from, reply);
+            }
+            Message::AppendEntries(args) => {
+                let reply = self.handle_append_entries(args);
+                self.send(from, Message::AppendEntriesReply(reply));
+            }
+            Message::AppendEntriesReply(reply) => {
+                self.handle_append_entries_reply(from, reply);
+            }
+            Message::InstallSnapshot(args) => {
+                let reply = self.handle_install_snapshot(args);
+                self.send(from, Message::InstallSnapshotReply(reply));
+            }
+            Message::InstallSnapshotReply(reply) => {
+                self.handle_install_snapshot_reply(from, reply);
+            }
+            Message::TimeoutNow(args) => self.handle_timeout_now(args),
+        }
+        self.advance_reads();
+        self.persist();
+    }
+
+    /// Write the hard state to stable storage if it has changed.
+    ///
+    /// Must be called before any queued message leaves the node.
+    /// If the write fails, all the queued messages are dropped, as it is
+    /// always safe not to reply, but unsafe to reply with a vote or term
+    /// that may be forgotten after a crash.
+    fn persist(&mut self) {
+        let hard_state = self.hard_state();
+        let Some(file) = &mut self.hard_state_file else {
+            return;
+        };
+        if hard_state == self.persisted {
+            return;
+        }
+        match file.save(&hard_state) {
+            Ok(()) => self.persisted = hard_state,
+            Err(e) => {
+                log::error!(
+                    "Node: {} failed to persist hard state: {}",
+                    self.socket_addr,
+                    e
+                );
+                self.messages.clear();
+            }
+        }
+    }
+
+    /// Decide whether to grant the vote to a candidate.
+    ///
+    /// At most one vote is granted in a term,
+    /// on a first-come-first-served basis,
+    /// and only to a candidate whose log is at least as up-to-date.
+    fn handle_request_vote(
+        &mut self,
+        args: RequestVoteArgs,
+    ) -> RequestVoteReply {
+        // The leader counts on its followers not to elect anyone else
+        // while it may hold a lease.
+        let leased = matches!(self.config.read_mode(), ReadMode::Lease { .. })
+            && !args.leadership_transfer
+            && self.hears_from_leader();
+        let vote_granted = args.term == self.current_term
+            && self.voted_for.is_none_or(|v| v == args.candidate)
+            && !leased
+            && self.is_up_to_date(args.last_log_index, args.last_log_term);
+
+        if vote_granted {
+            self.voted_for = Some(args.candidate);
+            self.election_timer.reset();
+            log::debug!(
+                "Node: {} voted for {} in term {}.",
+                self.socket_addr,
+                args.candidate,
+                self.current_term
+            );
+        }
+
+        RequestVoteReply { term: self.current_term, vote_granted }
+    }
+
+    /// Check whether a vote requested for a later term would only depose
+    /// a leader that is still heard from, such as by a node rejoining
+    /// after a partition. With Pre-Vote, CheckQuorum or leases, such a
+    /// request is ignored, without even adopting its term (Raft thesis
+    /// §9.6), unless the leader asked for the election.
+    fn is_disruptive(&self, args: &RequestVoteArgs) -> bool {
+        let guarded = self.config.pre_vote()
+            || self.config.check_quorum()
+            || matches!(self.config.read_mode(), ReadMode::Lease { .. });
+        guarded
+            && args.term > self.current_term
+            && !args.leadership_transfer
+            && self.hears_from_leader()
+    }
+
+    /// Count the vote, and become leader on receiving
+    /// votes from a majority of the cluster.
+    fn handle_request_vote_reply(
+        &mut self,
+        from: SocketAddr,
+        reply: RequestVoteReply,
+    ) {
+        if self.status != Status::Candidate
+            || reply.term != self.current_term
+            || !reply.vote_granted
+        {
+            return;
+        }
+
+        self.votes.insert(from);
+        if self.membership.has_quorum(|peer| self.votes.contains(peer)) {
+            self.become_leader();
+        }
+    }
+
+    /// Start an election at once on the request of the leader,
+    /// skipping the Pre-Vote round.
+    fn handle_timeout_now(&mut self, args: TimeoutNowArgs) {
+        if args.term != self.current_term
+            || self.is_leader()
+            || !self.membership.is_voter(&self.socket_addr)
+        {
+            return;
+        }
+        log::info!(
+            "Node: {} taking over the leadership from {}.",
+            self.socket_addr,
+            args.leader
+        );
+        self.become_candidate(true);
+    }
+
+    /// Decide whether a pre-candidate could win an election.
+    ///
+    /// The same rules as for a vote apply, but nothing is recorded,
+    /// and the Pre-Vote is refused while the leader is still heard from.
+    fn handle_pre_vote(&self, args: RequestVoteArgs) -> RequestVoteReply {
+        let vote_granted = args.term > self.current_term
+            && !self.hears_from_leader()
+            && self.is_up_to_date(args.last_log_index, args.last_log_term);
+
+        if vote_granted {
+            log::debug!(
+                "Node: {} granted pre-vote to {} for term {}.",
+                self.socket_addr,
+                args.candidate,
+                args.term
+            );
+            RequestVoteReply { term: args.term, vote_granted }
+        } else {
+            RequestVoteReply { term: self.current_term, vote_granted }
+        }
+    }
+
+    /// Count the Pre-Vote, and start a real election on receiving
+    /// Pre-Votes from a majority of the cluster.
+    fn handle_pre_vote_reply(
+        &mut self,
+        from: SocketAddr,
+        reply: RequestVoteReply,
+    ) {
+        if self.status != Status::PreCandidate
+            || reply.term != self.current_term + 1
+            || !reply.vote_granted
+        {
+            return;
+        }
+
+        self.votes.insert(from);
+        if self.membership.has_quorum(|peer| self.votes.contains(peer)) {
+            self.become_candidate(false);
+        }
+    }
+
+    /// Check whether a leader has been heard from
+    /// within the minimum election timeout.
+    fn hears_from_leader(&self) -> bool {
+        if self.is_leader() {
+            return true;
+        }
+        let timeout = *self.config.election_timeout().start();
+        self.leader_contact.is_some_and(|contact| {
+            contact.elapsed() < Duration::from_millis(timeout.into())
+        })
+    }
+
+    /// Check whether a log ending with the given index and term is
+    /// at least as up-to-date as the log of this node.
+    ///
+    /// The log with the later term of the last entry is more up-to-date.
+    /// If the logs end with the same term, the longer log is.
+    fn is_up_to_date(
+        &self,
+        last_log_index: Index,
+        last_log_term: Term,
+    ) -> bool {
+        (last_log_term, last_log_index)
+            >= (self.last_log_term(), self.last_log_index())
+    }
+
+    /// Accept entries from the leader if the log contains an entry
+    /// matching `prev_log_index` and `prev_log_term`.
+    ///
+    /// An existing entry conflicting with a new one (same index but
+    /// different term) is deleted together with all that follow it.
+    fn handle_append_entries(
+        &mut self,
+        args: AppendEntriesArgs,
+    ) -> AppendEntriesReply {
+        let mut reply = AppendEntriesReply {
+            term: self.current_term,
+            success: false,
+            match_index: 0,
+            round: args.round,
+            conflict_term: 0,
+            conflict_index: 0,
+        };
+        if args.term < self.current_term {
+            return reply;
+        }
+
+        // A candidate learns that another node has won the election.
+        if self.status.is_candidate() {
+            self.become_follower(args.term);
+        }
+        self.leader = Some(args.leader);
+        self.leader_contact = Some(Instant::now());
+        self.election_timer.reset();
+
+        if self.term_at(args.prev_log_index) != Some(args.prev_log_term) {
+            log::debug!(
+                "Node: {} has no entry {} in term {} from {}.",
+                self.socket_addr,
+                args.prev_log_index,
+                args.prev_log_term,
+                args.leader
+            );
+            (reply.conflict_term, reply.conflict_index) =
+                self.find_conflict(args.prev_log_index);
+            return reply;
+        }
+
+        let last_new_index = args.prev_log_index + args.entries.len() as Index;
+        if let Err(e) = self.store_entries(args.entries) {
+            log::error!(
+                "Node: {} failed to store entries: {}",
+                self.socket_addr,
+                e
+            );
+            return reply;
+        }
+
+        // Entries after the last new one may not match the leader,
+        // and the commit index never goes back.
+        let commit_index = args.leader_commit.min(last_new_index);
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+            self.apply_committed();
+        }
+
+        reply.success = true;
+        reply.match_index = last_new_index;
+        reply
+    }
+
+    /// Get the term of the entry at the given index, which does not match
+    /// the leader's, with the first index of that term in the log.
+    ///
+    /// Without an entry at that index, the term is `0` and the index is
+    /// the one after the last entry, or after the snapshot if the entry
+    /// has been compacted.
+    fn find_conflict(&self, index: Index) -> (Term, Index) {
+        if index > self.last_log_index() {
+            return (0, self.last_log_index() + 1);
+        }
+        let Some(term) = self.term_at(index) else {
+            return (0, self.snapshot.last_included_index + 1);
+        };
+        let mut first_index = index;
+        while first_index > self.log.first_index()
+            && self.term_at(first_index - 1) == Some(term)
+        {
+            first_index -= 1;
+        }
+        (term, first_index)
+    }
+
+    /// Store the entries received from the leader, skipping those already
+    /// in the log, and truncating the log at the first conflicting one.
+    ///
+    /// A membership entry takes effect as soon as it is stored, and
+    /// stops as soon as it is truncated.
+    fn store_entries(&mut self, mut entries: Vec<Entry>) -> io::Result<()> {
+        let mut present = 0;
+        let mut truncated = false;
+        for entry in &entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => present += 1,
+                Some(_) => {
+                    log::debug!(
+                        "Node: {} truncated conflicting entries from {}.",
+                        self.socket_addr,
+                        entry.index
+                    );
+                    self.log.truncate_suffix(entry.index)?;
+                    truncated = entry.index <= self.membership_index;
+                    break;
+                }
+                None => break,
+            }
+        }
+        entries.drain(..present);
+        let result = self.log.append(&entries);
+        let changed =
+            entries.iter().any(|entry| entry.kind == EntryKind::Membership);
+        if truncated || changed {
+            self.reload_membership();
+        }
+        result
+    }
+
+    /// Track the progress of the peer, and retry with an earlier entry
+    /// if the peer rejected the entries.
+    fn handle_append_entries_reply(
+        &mut self,
+        from: SocketAddr,
+        reply: AppendEntriesReply,
+    ) {
+        // A reply from a removed peer must not track it again.
+        if !self.is_leader()
+            || reply.term != self.current_term
+            || !self.peers.contains(&from)
+        {
+            return;
+        }
+        self.recent_active.insert(from);
+        let acked_round = self.acked_round.entry(from).or_insert(0);
+        *acked_round = (*acked_round).max(reply.round);
+        self.extend_lease();
+
+        if reply.success {
+            let match_index = self.match_index.entry(from).or_insert(0);
+            *match_index = (*match_index).max(reply.match_index);
+            let match_index = *match_index;
+            let inflight = self.inflight.entry(from).or_default();
+            while inflight.front().is_some_and(|&last| last <= match_index) {
+                inflight.pop_front();
+            }
+            let next_index = self.next_index.entry(from).or_insert(1);
+            *next_index = (*next_index).max(match_index + 1);
+            // The peer has all the entries before `next_index`,
+            // so entries can be sent without waiting. Any batch still
+            // deemed in flight was sent while probing, and is lost.
+            if *next_index == match_index + 1 {
+                self.probing.remove(&from);
+                if let Some(inflight) = self.inflight.get_mut(&from) {
+                    inflight.clear();
+                }
+            }
+            self.advance_commit_index();
+            self.send_append_entries(from);
+            if match_index == self.last_log_index()
+                && self.transferee() == Some(from)
+            {
+                self.send_timeout_now(from);
+            }
+        } else {
+            // Skip the whole conflicting term, but never go back past
+            // an entry known to be replicated.
+            let match_index = self.match_index.get(&from).copied().unwrap_or(0);
+            let hint = self.next_index_hint(&reply);
+            let next_index = self.next_index.entry(from).or_insert(1);
+            *next_index = hint.min(*next_index).max(match_index + 1);
+            self.probing.insert(from);
+            if let Some(inflight) = self.inflight.get_mut(&from) {
+                inflight.clear();
+            }
+            self.send_append_entries(from);
+        }
+    }
+
+    /// Get the next index to try after a rejection: after the last entry
+    /// of the conflicting term if the leader has it, otherwise the first
+    /// index of that term in the follower's log.
+    fn next_index_hint(&self, reply: &AppendEntriesReply) -> Index {
+        if reply.conflict_term == 0 {
+            return reply.conflict_index;
+        }
+        let mut index = self.last_log_index();
+        while index > self.snapshot.last_included_index {
+            match self.term_at(index) {
+                Some(term) if term == reply.conflict_term => return index + 1,
+                Some(term) if term < reply.conflict_term => break,
+                _ => index -= 1,
+            }
+        }
+        reply.conflict_index
+    }
+
+    /// Send the entries starting from the peer's `next_index`,
+    /// or the snapshot if some of them have been discarded.
+    ///
+    /// A single snapshot chunk is in flight at a time, the next one is
+    /// sent on its reply. It is only sent again here once it is deemed
+    /// lost, after the minimum election timeout.
+    ///
+    /// Entries are sent in batches of at most [`Config::max_batch_size()`],
+    /// as many as the in-flight window of the peer allows. While probing,
+    /// a single batch is in flight, and `next_index` only moves once it is
+    /// accepted. Otherwise `next_index` moves past each batch sent.
+    ///
+    /// Return whether anything was sent.
+    fn send_append_entries(&mut self, peer: SocketAddr) -> bool {
+        let batch_size = Index::from(self.config.max_batch_size());
+        let mut sent = false;
+        loop {
+            let next_index = self.next_index.get(&peer).copied().unwrap_or(1);
+            if next_index <= self.snapshot.last_included_index {
+                let offset = match self.snapshot_transfers.get(&peer) {
+                    None => 0,
+                    Some(transfer)
+                        if transfer.sent_at.elapsed()
+                            < self.snapshot_chunk_timeout() =>
+                    {
+                        return sent;
+                    }
+                    Some(transfer)
+                        if transfer.last_included_index
+                            == self.snapshot.last_included_index =>
+                    {
+                        transfer.offset
+                    }
+                    // The snapshot has been replaced in the meantime.
+                    Some(_) => 0,
+                };
+                self.send_snapshot(peer, offset);
+                return true;
+            }
+            let last_log_index = self.last_log_index();
+            if next_index > last_log_index || self.inflight_full(peer) {
+                return sent;
+            }
+            let last_index = last_log_index.min(next_index + batch_size - 1);
+            let prev_log_index = next_index - 1;
+            let args = AppendEntriesArgs {
+                term: self.current_term,
+                leader: self.socket_addr,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+                entries: self.log.entries(next_index, last_index + 1),
+                leader_commit: self.commit_index,
+                round: self.round,
+            };
+            self.send(peer, Message::AppendEntries(args));
+            self.inflight.entry(peer).or_default().push_back(last_index);
+            sent = true;
+            if self.probing.contains(&peer) {
+                return sent;
+            }
+            self.next_index.insert(peer, last_index + 1);
+        }
+    }
+
+    /// Send an AppendEntries without entries as a heartbeat.
+    fn send_empty_append_entries(&mut self, peer: SocketAddr) {
+        let prev_log_index =
+            self.next_index.get(&peer).copied().unwrap_or(1) - 1;
+        let args = AppendEntriesArgs {
+            term: self.current_term,
+            leader: self.socket_addr,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+            entries: Vec::new(),
+            leader_commit: self.commit_index,
+            round: self.round,
+        };
+        self.send(peer, Message::AppendEntries(args));
+    }
+
+    /// Check whether no more AppendEntries can be sent to the peer
+    /// before some in flight are acknowledged.
+    fn inflight_full(&self, peer: SocketAddr) -> bool {
+        let window = if self.probing.contains(&peer) {
+            1
+        } else {
+            self.config.max_inflight() as usize
+        };
+        self.inflight
+            .get(&peer)
+            .is_some_and(|inflight| inflight.len() >= window)
+    }
+
+    /// Get the time after which a snapshot chunk in flight is deemed lost.
+    fn snapshot_chunk_timeout(&self) -> Duration {
+        let min = *self.config.election_timeout().start();
+        Duration::from_millis(u64::from(min))
+    }
+
+    /// Send the chunk of the snapshot at the offset to the peer,
+    /// as the only one in flight.
+    fn send_snapshot(&mut self, peer: SocketAddr, offset: u64) {
+        let len = self.snapshot.data.len();
+        // The snapshot may have been replaced by a smaller one.
+        let start = if offset as usize > len { 0 } else { offset as usize };
+        let end = len.min(start + self.config.snapshot_chunk_size() as usize);
+        let args = InstallSnapshotArgs {
+            term: self.current_term,
+            leader: self.socket_addr,
+            last_included_index: self.snapshot.last_included_index,
+            last_included_term: self.snapshot.last_included_term,
+            membership: self.snapshot.membership.clone(),
+            offset: start as u64,
+            data: self.snapshot.data[start..end].to_vec(),
+            done: end == len,
+        };
+        log::trace!(
+            "Node: {} sent snapshot chunk at {} to {}.",
+            self.socket_addr,
+            start,
+            peer
+        );
+        self.snapshot_transfers.insert(
+            peer,
+            SnapshotTransfer {
+                last_included_index: self.snapshot.last_included_index,
+                offset: start as u64,
+                sent_at: Instant::now(),
+            },
+        );
+        self.send(peer, Message::InstallSnapshot(args));
+    }
+
+    /// Receive a chunk of a snapshot from the leader,
+    /// and install the snapshot once the last chunk arrives.
+    ///
+    /// Chunks must arrive in order. Any other chunk is dropped, such as a
+    /// duplicate, and the reply tells the leader where to resume.
+    fn handle_install_snapshot(
+        &mut self,
+        args: InstallSnapshotArgs,
+    ) -> InstallSnapshotReply {
+        let mut reply = InstallSnapshotReply {
+            term: self.current_term,
+            last_included_index: args.last_included_index,
+            chunk_offset: args.offset,
+            offset: 0,
+            done: false,
+        };
+        if args.term < self.current_term {
+            return reply;
+        }
+
+        if self.status.is_candidate() {
+            self.become_follower(args.term);
+        }
+        self.leader = Some(args.leader);
+        self.leader_contact = Some(Instant::now());
+        self.election_timer.reset();
+
+        // Committed entries are the same in every log,
+        // so a snapshot covering no more of them is useless.
+        if args.last_included_index <= self.commit_index {
+            reply.done = true;
+            return reply;
+        }
+
+        let incoming = match &mut self.incoming_snapshot {
+            Some(snapshot)
+                if snapshot.last_included_index == args.last_included_index
+                    && snapshot.last_included_term
+                        == args.last_included_term =>
+            {
+                snapshot
+            }
+            incoming => incoming.insert(Snapshot {
+                last_included_index: args.last_included_index,
+                last_included_term: args.last_included_term,
+                membership: args.membership.clone(),
+                data: Vec::new(),
+            }),
+        };
+        if incoming.data.len() as u64 != args.offset {
+            reply.offset = incoming.data.len() as u64;
+            return reply;
+        }
+        incoming.data.extend_from_slice(&args.data);
+        reply.offset = incoming.data.len() as u64;
+        if !args.done {
+            return reply;
+        }
+
+        let snapshot = self.incoming_snapshot.take().unwrap_or_default();
+        if let Some(file) = &mut self.snapshot_file {
+            if let Err(e) = file.save(&snapshot) {
+                log::error!(
+                    "Node: {} failed to save snapshot: {}",
+                    self.socket_addr,
+                    e
+                );
+                reply.offset = 0;
+                return reply;
+            }
+        }
+        if let Err(e) = self.install_snapshot(snapshot) {
+            log::error!(
+                "Node: {} failed to install snapshot: {}",
+                self.socket_addr,
+                e
+            );
+            reply.offset = 0;
+            return reply;
+        }
+        reply.done = true;
+        reply
+    }
+
+    /// Replace the state machine with the snapshot, and discard the log
+    /// entries it covers.
+    ///
+    /// Entries following the snapshot are kept if the log contains
+    /// the last entry covered by the snapshot, otherwise the whole log
+    /// is discarded.
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        let index = snapshot.last_included_index;
+        // The log may already start right after the snapshot,
+        // when it was compacted before a restart.
+        let contains_last = self.log.first_index() == index + 1
+            || self.log.term(index) == Some(snapshot.last_included_term);
+        if !contains_last {
+            self.log.truncate_suffix(self.log.first_index())?;
+        }
+        self.log.compact(index)?;
+        self.state_machine.restore(&snapshot.data);
+        self.last_applied = index;
+        self.commit_index = self.commit_index.max(index);
+        self.snapshot = snapshot;
+        self.reload_membership();
+        log::info!(
+            "Node: {} installed snapshot up to entry {}.",
+            self.socket_addr,
+            index
+        );
+        Ok(())
+    }
+
+    /// Track the progress of the snapshot sent to the peer,
+    /// and send the next chunk or the entries that follow it.
+    fn handle_install_snapshot_reply(
+        &mut self,
+        from: SocketAddr,
+        reply: InstallSnapshotReply,
+    ) {
+        if !self.is_leader()
+            || reply.term != self.current_term
+            || !self.peers.contains(&from)
+        {
+            return;
+        }
+        self.recent_active.insert(from);
+
+        if reply.done {
+            self.snapshot_transfers.remove(&from);
+            let match_index = self.match_index.entry(from).or_insert(0);
+            *match_index = (*match_index).max(reply.last_included_index);
+            let match_index = *match_index;
+            self.next_index.insert(from, match_index + 1);
+            self.probing.remove(&from);
+            if let Some(inflight) = self.inflight.get_mut(&from) {
+                inflight.clear();
+            }
+            self.advance_commit_index();
+            self.send_append_entries(from);
+            return;
+        }
+
+        // Only the reply to the chunk in flight sends the next one,
+        // any other is a duplicate.
+        let Some(transfer) = self.snapshot_transfers.get(&from) else {
+            return;
+        };
+        if reply.last_included_index != transfer.last_included_index
+            || reply.chunk_offset != transfer.offset
+        {
+            return;
+        }
+        // Resume where the peer stopped, or start over if the
+        // snapshot has been replaced in the meantime.
+        let offset =
+            if reply.last_included_index == self.snapshot.last_included_index {
+                reply.offset
+            } else {
+                0
+            };
+        self.send_snapshot(from, offset);
+    }
+
+    /// Commit the highest entry of the current term
+    /// that has been replicated on a majority of the cluster.
+    ///
+    /// Entries from previous terms are never committed by counting
+    /// replicas, they are committed indirectly along with it.
+    fn advance_commit_index(&mut self) {
+        let index = self.membership.quorum_index(|peer| {
+            if *peer == self.socket_addr {
+                self.last_log_index()
+            } else {
+                self.match_index.get(peer).copied().unwrap_or(0)
+            }
+        });
+        if index <= self.commit_index
+            || self.term_at(index) != Some(self.current_term)
+        {
+            return;
+        }
+        log::debug!(
+            "Node: {} committed entries up to {}.",
+            self.socket_addr,
+            index
+        );
+        self.commit_index = index;
+        self.apply_committed();
+        self.advance_membership();
+    }
+
+    /// Check that the leader can start a membership change,
+    /// i.e. the previous one is committed.
+    fn check_membership_change(&self) -> Result<(), MembershipError> {
+        if !self.is_leader() {
+            return Err(MembershipError::NotLeader {
+                leader_hint: self.leader,
+            });
+        }
+        if self.membership.is_joint()
+            || self.membership_index > self.commit_index
+        {
+            return Err(MembershipError::InProgress);
+        }
+        Ok(())
+    }
+
+    /// Append a membership entry and start replicating it.
+    fn commit_membership(
+        &mut self,
+        membership: Membership,
+    ) -> Result<Index, MembershipError> {
+        let index = self
+            .append_membership(membership)
+            .map_err(|e| MembershipError::Storage(e.kind()))?;
+        self.advance_commit_index();
+        // New members are sent a heartbeat to find where they stand.
+        self.replicate();
+        self.persist();
+        Ok(index)
+    }
+
+    /// Go on with the membership change once its last entry is
+    /// committed: leave the joint membership, or step down if the
+    /// leader is no longer a voter.
+    fn advance_membership(&mut self) {
+        if !self.is_leader() || self.membership_index > self.commit_index {
+            return;
+        }
+        if self.membership.is_joint() {
+            let membership = Membership::new(self.membership.voters.clone())
+                .set_learners(self.membership.learners.clone());
+            if let Err(e) = self.append_membership(membership) {
+                log::error!(
+                    "Node: {} failed to append membership: {}",
+                    self.socket_addr,
+                    e
+                );
+                return;
+            }
+            self.advance_commit_index();
+            self.replicate();
+        } else if !self.membership.is_voter(&self.socket_addr) {
+            log::info!(
+                "Node: {} removed from the cluster, stepping down.",
+                self.socket_addr
+            );
+            self.become_follower(self.current_term);
+            self.leader = None;
+        }
+    }
+
+    /// Append a membership entry to the log of the leader,
+    /// taking effect at once.
+    fn append_membership(
+        &mut self,
+        membership: Membership,
+    ) -> io::Result<Index> {
+        let index = self.last_log_index() + 1;
+        let entry = Entry::membership(self.current_term, index, &membership);
+        self.log.append(&[entry])?;
+        self.use_membership(membership, index);
+        Ok(index)
+    }
+
+    /// Use the latest membership in the log,
+    /// or the one of the snapshot if there is none.
+    fn reload_membership(&mut self) {
+        let (index, membership) = self.membership_at(self.last_log_index());
+        self.use_membership(membership, index);
+    }
+
+    /// Get the membership as of the given index, with the index of the
+    /// entry or snapshot holding it.
+    fn membership_at(&self, index: Index) -> (Index, Membership) {
+        let first_index = self.log.first_index();
+        for index in (first_index..=index).rev() {
+            let Some(entry) = self.log.entry(index) else {
+                continue;
+            };
+            if entry.kind != EntryKind::Membership {
+                continue;
+            }
+            match Membership::decode(&entry.data) {
+                Ok(membership) => return (index, membership),
+                Err(e) => log::error!(
+                    "Node: {} ignored membership entry {}: {}",
+                    self.socket_addr,
+                    index,
+                    e
+                ),
+            }
+        }
+        let snapshot = &self.snapshot;
+        (snapshot.last_included_index, snapshot.membership.clone())
+    }
+
+    /// Replace the membership, and the peers the leader replicates to.
+    fn use_membership(&mut self, membership: Membership, index: Index) {
+        if membership != self.membership {
+            log::info!(
+                "Node: {} uses membership {:?} from entry {}.",
+                self.socket_addr,
+                membership,
+                index
+            );
+        }
+        self.peers = membership
+            .members()
+            .into_iter()
+            .filter(|&peer| peer != self.socket_addr)
+            .collect();
+        if self.is_leader() {
+            let next_index = self.last_log_index() + 1;
+            for &peer in &self.peers {
+                if let hash_map::Entry::Vacant(entry) =
+                    self.next_index.entry(peer)
+                {
+                    entry.insert(next_index);
+                    self.match_index.insert(peer, 0);
+                    self.probing.insert(peer);
+                }
+            }
+            let peers = &self.peers;
+            self.next_index.retain(|peer, _| peers.contains(peer));
+            self.match_index.retain(|peer, _| peers.contains(peer));
+            self.inflight.retain(|peer, _| peers.contains(peer));
+            self.probing.retain(|peer| peers.contains(peer));
+            self.snapshot_transfers.retain(|peer, _| peers.contains(peer));
+            self.acked_round.retain(|peer, _| peers.contains(peer));
+            self.recent_active.retain(|peer| peers.contains(peer));
+        }
+        self.membership = membership;
+        self.membership_index = index;
+    }
+
+    /// Start a new heartbeat round, sent with the next messages.
+    fn next_round(&mut self) {
+        self.round += 1;
+        if let ReadMode::Lease { .. } = self.config.read_mode() {
+            // Rounds older than an election timeout cannot give a lease.
+            let timeout = *self.config.election_timeout().start();
+            let timeout = Duration::from_millis(timeout.into());
+            while let Some(&(_, sent)) = self.rounds.front() {
+                if sent.elapsed() < timeout {
+                    break;
+                }
+                self.rounds.pop_front();
+            }
+            self.rounds.push_back((self.round, Instant::now()));
+        }
+    }
+
+    /// Extend the lease up to an election timeout, minus the clock
+    /// drift margin, after the latest round acknowledged by a majority
+    /// was sent. Not while handing over the leadership.
+    fn extend_lease(&mut self) {
+        let ReadMode::Lease { clock_drift } = self.config.read_mode() else {
+            return;
+        };
+        let acked = self.membership.quorum_index(|peer| {
+            if *peer == self.socket_addr {
+                self.round
+            } else {
+                self.acked_round.get(peer).copied().unwrap_or(0)
+            }
+        });
+        let mut sent = None;
+        while let Some(&(round, at)) = self.rounds.front() {
+            if round > acked {
+                break;
+            }
+            sent = Some(at);
+            self.rounds.pop_front();
+        }
+        if let Some(sent) = sent.filter(|_| self.transfer.is_none()) {
+            let timeout = *self.config.election_timeout().start();
+            let lease = Duration::from_millis((timeout - clock_drift).into());
+            self.lease = Some(sent + lease);
+        }
+    }
+
+    /// Check whether the leader holds a lease.
+    fn holds_lease(&self) -> bool {
+        self.lease.is_some_and(|lease| Instant::now() < lease)
+    }
+
+    /// Serve the pending reads whose round has been acknowledged by a
+    /// majority, and whose read index has been applied.
+    fn advance_reads(&mut self) {
+        if self.pending_reads.is_empty() {
+            return;
+        }
+        // Reads requested before the leader committed an entry of its
+        // term wait for it, as earlier entries may be committed already.
+        if self.term_at(self.commit_index) == Some(self.current_term) {
+            for read in &mut self.pending_reads {
+                read.index.get_or_insert(self.commit_index);
+            }
+        }
+        let acked_round = &self.acked_round;
+        let last_applied = self.last_applied;
+        // Rounds increase with the requests,
+        // so only a prefix of the reads can be ready.
+        while let Some(read) = self.pending_reads.front() {
+            let confirmed = self.membership.has_quorum(|peer| {
+                *peer == self.socket_addr
+                    || acked_round.get(peer).is_some_and(|&r| r >= read.round)
+            });
+            let ready = confirmed
+                && read.index.is_some_and(|index| index <= last_applied);
+            if !ready {
+                break;
+            }
+            let read = self.pending_reads.pop_front().unwrap();
+            let _ = read.ready.send(Ok(read.index.unwrap_or(0)));
+        }
+    }
+
+    /// Fail all the pending reads, after losing the leadership.
+    fn abort_reads(&mut self) {
+        for read in self.pending_reads.drain(..) {
+            let _ = read.ready.send(Err(ReadError::LeadershipLost));
+        }
+    }
+
+    /// Send the response of the state machine to the proposal
+    /// of the entry at the given index, if any.
+    fn complete_proposal(&mut self, index: Index, response: Response) {
+        if self.pending_proposals.front().is_some_and(|p| p.index == index) {
+            let proposal = self.pending_proposals.pop_front().unwrap();
+            let _ = proposal.ready.send(Ok(response));
+        }
+    }
+
+    fn abort_proposals(&mut self) {
+        for proposal in self.pending_proposals.drain(..) {
+            let _ = proposal.ready.send(Err(ProposeError::TermChanged));
+        }
+    }
+
+    /// Apply all the committed entries that have not been applied yet,
+    /// and take a snapshot if enough of them have been applied since the
+    /// last one.
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            let Some(entry) = self.log.entry(self.last_applied + 1) else {
+                break;
+            };
+            if entry.kind == EntryKind::Normal {
+                let response = self.state_machine.apply(&entry);
+                self.complete_proposal(entry.index, response);
+            }
+            self.last_applied = entry.index;
+            log::trace!(
+                "Node: {} applied entry {}.",
+                self.socket_addr,
+                self.last_applied
+            );
+        }
+
+        let threshold = self.config.snapshot_threshold();
+        if threshold > 0
+            && self.last_applied - self.snapshot.last_included_index
+                >= threshold
+        {
+            if let Err(e) = self.take_snapshot() {
+                log::error!(
+                    "Node: {} failed to take snapshot: {}",
+                    self.socket_addr,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Get the term of the entry at the given index,
+    /// `None` if not in the log or replaced by the snapshot.
+    ///
+    /// Index `0` stands for the empty log, so its term is always `0`.
+    /// The last entry replaced by the snapshot keeps its term.
+    fn term_at(&self, index: Index) -> Option<Term> {
+        let snapshot = &self.snapshot;
+        match index {
+            0 => Some(0),
+            _ if index == snapshot.last_included_index => {
+                Some(snapshot.last_included_term)
+            }
+            _ if index < snapshot.last_included_index => None,
+            _ => self.log.term(index),
+        }
+    }
+
+    fn become_follower(&mut self, term: Term) {
+        self.status = Status::Follower;
+        self.abort_reads();
+        self.abort_proposals();
+        self.rounds.clear();
+        self.lease = None;
+        self.transfer = None;
+        if term != self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.incoming_snapshot = None;
+        }
+        self.votes.clear();
+        self.election_timer.reset();
+        self.heartbeat_timer.stop();
+    }
+
+    fn become_pre_candidate(&mut self) {
+        self.status = Status::PreCandidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.socket_addr]);
+        self.election_timer.reset();
+        log::info!(
+            "Node: {} became pre-candidate in term {}.",
+            self.socket_addr,
+            self.current_term
+        );
+
+        // A single node cluster needs no Pre-Vote.
+        if self.membership.has_quorum(|peer| self.votes.contains(peer)) {
+            self.become_candidate(false);
+            return;
+        }
+
+        let args = RequestVoteArgs {
+            term: self.current_term + 1,
+            candidate: self.socket_addr,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+            leadership_transfer: false,
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, Message::PreVote(args.clone()));
+        }
+    }
+
+    fn become_candidate(&mut self, leadership_transfer: bool) {
+        self.status = Status::Candidate;
+        self.current_term += 1;
+        self.voted_for = Some(self.socket_addr);
+        self.leader = None;
+        self.incoming_snapshot = None;
+        self.votes = HashSet::from([self.socket_addr]);
+        self.election_timer.reset();
+        log::info!(
+            "Node: {} became candidate in term {}.",
+            self.socket_addr,
+            self.current_term
+        );
+
+        // A single node cluster elects itself immediately.
+        if self.membership.has_quorum(|peer| self.votes.contains(peer)) {
+            self.become_leader();
+            return;
+        }
+
+        let args = RequestVoteArgs {
+            term: self.current_term,
+            candidate: self.socket_addr,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+            leadership_transfer,
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, Message::RequestVote(args.clone()));
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.status = Status::Leader;
+        self.leader = Some(self.socket_addr);
+        if self.config.check_quorum() {
+            self.election_timer.reset();
+        } else {
+            self.election_timer.cancel();
+        }
+        self.heartbeat_timer.start();
+        self.recent_active.clear();
+        self.acked_round.clear();
+        self.next_round();
+        log::info!(
+            "Node: {} became leader in term {}.",
+            self.socket_addr,
+            self.current_term
+        );
+
+        let next_index = self.last_log_index() + 1;
+        self.next_index =
+            self.peers.iter().map(|&peer| (peer, next_index)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        self.inflight.clear();
+        self.probing = self.peers.iter().copied().collect();
+        self.snapshot_transfers.clear();
+        // Committing an entry of the new term commits the entries left
+        // by the previous leaders, without waiting for a command.
+        self.append_noop();
+        self.advance_commit_index();
+        self.replicate();
+        // A change left halfway by the previous leader goes on.
+        self.advance_membership();
+    }
+
+    /// Append a no-op entry to the log of the leader.
+    fn append_noop(&mut self) {
+        let index = self.last_log_index() + 1;
+        let entry = Entry::noop(self.current_term, index);
+        if let Err(e) = self.log.append(&[entry]) {
+            log::error!(
+                "Node: {} failed to append no-op entry {}: {}",
+                self.socket_addr,
+                index,
+                e
+            );
+        }
+    }
+
+    fn send_timeout_now(&mut self, target: SocketAddr) {
+        let args = TimeoutNowArgs {
+            term: self.current_term,
+            leader: self.socket_addr,
+        };
+        self.send(target, Message::TimeoutNow(args));
+    }
+
+    fn send(&mut self, to: SocketAddr, message: Message) {
+        self.messages.push((to, message));
+    }
+}
+
+/// All possible status (states) of a Raft node.
+///
+/// As the word **State** have the same wording with *persistent* and
+/// *volatile state* in Node's data, we use **Status** instead.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Follower status. The default status of a new node.
+    Follower,
+
+    /// Pre-candidate status, only used with Pre-Vote enabled.
+    /// The node checks that it could win before starting an election.
+    PreCandidate,
+
+    /// Candidate status.
+    Candidate,
+
+    /// Leader status.
+    Leader,
+}
+
+impl Status {
+    pub fn is_leader(&self) -> bool {
+        matches!(self, Status::Leader)
+    }
+
+    /// Check if the node is trying to become the leader,
+    /// either as a pre-candidate or as a candidate.
+    pub fn is_candidate(&self) -> bool {
+        matches!(self, Status::PreCandidate | Status::Candidate)
+    }
+}
+
+/// Reasons why the leadership cannot be handed over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// The node is not the leader. The leader of the current term is
+    /// given if known, so that the transfer can be requested there.
+    NotLeader { leader_hint: Option<SocketAddr> },
+
+    /// The target is not a peer of the node.
+    UnknownTarget(SocketAddr),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::NotLeader { leader_hint: Some(leader) } => {
+                write!(f, "Not the leader, try {}", leader)
+            }
+            TransferError::NotLeader { leader_hint: None } => {
+                write!(f, "Not the leader")
+            }
+            TransferError::UnknownTarget(target) => {
+                write!(f, "Unknown transfer target: {}", target)
+            }
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LOCAL_ADDR: &str = "127.0.0.0:2024";
+
+    /// A state machine that records the data of all applied entries.
+    #[derive(Default)]
+    struct Recorder {
+        applied: Vec<Vec<u8>>,
+    }
+
+    impl StateMachine for Recorder {
+        fn apply(&mut self, entry: &Entry) -> Vec<u8> {
+            self.applied.push(entry.data.clone());
+            entry.data.clone()
+        }
+
+        /// Each data is prefixed by its length on 4 bytes.
+        fn snapshot(&self) -> Vec<u8> {
+            let mut snapshot = Vec::new();
+            for data in &self.applied {
+                snapshot.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                snapshot.extend_from_slice(data);
+            }
+            snapshot
+        }
+
+        fn restore(&mut self, mut snapshot: &[u8]) {
+            self.applied.clear();
+            while !snapshot.is_empty() {
+                let (len, rest) = snapshot.split_at(4);
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                self.applied.push(rest[..len].to_vec());
+                snapshot = &rest[len..];
+            }
+        }
+    }
+
+    fn node(port: u16) -> Node<Recorder> {
+        Node::new(addr(port), Recorder::default())
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Create a cluster of `n` nodes listening on ports `1..=n`.
+    fn cluster(n: u16) -> Vec<Node<Recorder>> {
+        (1..=n).map(|i| node(i).set_peers((1..=n).map(addr))).collect()
+    }
+
+    /// Create a cluster of `n` nodes with the given configuration.
+    fn cluster_with(n: u16, config: Config) -> Vec<Node<Recorder>> {
+        cluster(n)
+            .into_iter()
+            .map(|node| node.set_config(config.clone()).unwrap())
+            .collect()
+    }
+
+    /// Deliver messages between nodes until no more are produced.
+    fn deliver(nodes: &mut [Node<Recorder>]) {
+        loop {
+            let mut messages = Vec::new();
+            for node in nodes.iter_mut() {
+                let from = *node.socket_addr();
+                for (to, message) in node.take_messages() {
+                    messages.push((from, to, message));
+                }
+            }
+            if messages.is_empty() {
+                return;
+            }
+            for (from, to, message) in messages {
+                if let Some(node) =
+                    nodes.iter_mut().find(|n| *n.socket_addr() == to)
+                {
+                    node.step(from, message);
+                }
+            }
+        }
+    }
+
+    /// Make sure that a new node is created with:
+    /// - Status::Follower
+    /// - Term 0
+    #[test]
+    fn test_new() {
+        let node = Node::new(LOCAL_ADDR.parse().unwrap(), Recorder::default());
+        assert!(matches!(node.status(), Status::Follower));
+        assert_eq!(node.current_term(), 0);
+    }
+
+    #[test]
+    fn test_socket() {
+        let node = Node::new(LOCAL_ADDR.parse().unwrap(), Recorder::default());
+        assert!(node.socket_addr().ip().is_loopback());
+        assert_eq!(node.socket_addr().port(), 2024);
+    }
+
+    #[test]
+    fn test_is_leader() {
+        let leader = Status::Leader;
+        assert!(leader.is_leader());
+
+        let follower = Status::Follower;
+        assert!(!follower.is_leader());
+
+        let candidate = Status::Candidate;
+        assert!(!candidate.is_leader());
+    }
+
+    #[test]
+    fn test_set_peers() {
+        let node = node(1).set_peers([addr(1), addr(2), addr(2)]);
+        assert_eq!(node.peers(), &[addr(2)]);
+        assert_eq!(node.membership().voters.len(), 2);
+    }
+
+    #[test]
+    fn test_single_node_election() {
+        let mut node = node(1);
+        node.start_election();
+        assert!(node.is_leader());
+        assert_eq!(node.current_term(), 1);
+        assert!(node.take_messages().is_empty());
+    }
+
+    #[test]
+    fn test_start_election() {
+        let mut nodes = cluster(3);
+        nodes[0].start_election();
+        assert_eq!(nodes[0].status(), &Status::Candidate);
+        assert_eq!(nodes[0].current_term(), 1);
+        assert_eq!(nodes[0].voted_for(), Some(addr(1)));
+
+        let messages = nodes[0].take_messages();
+        assert_eq!(messages.len(), 2);
+        for (_, message) in messages {
+            assert_eq!(
+                message,
+                Message::RequestVote(RequestVoteArgs {
+                    term: 1,
+                    candidate: addr(1),
+                    last_log_index: 0,
+                    last_log_term: 0,
+                    leadership_transfer: false,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_win_election() {
+        let mut nodes = cluster(3);
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        for node in &nodes[1..] {
+            assert_eq!(node.status(), &Status::Follower);
+            assert_eq!(node.current_term(), 1);
+            assert_eq!(node.voted_for(), Some(addr(1)));
+        }
+    }
+
+    #[test]
+    fn test_vote_once_per_term() {
+        let mut nodes = cluster(3);
+        nodes[0].start_election();
+        nodes[1].start_election();
+        // Node 3 hears from node 1 first and rejects node 2.
+        let to_3 = |(to, _): &(SocketAddr, Message)| *to == addr(3);
+        let from_1 = nodes[0].take_messages().into_iter().find(to_3).unwrap();
+        let from_2 = nodes[1].take_messages().into_iter().find(to_3).unwrap();
+        nodes[2].step(addr(1), from_1.1);
+        nodes[2].step(addr(2), from_2.1);
+        assert_eq!(nodes[2].voted_for(), Some(addr(1)));
+
+        let replies = nodes[2].take_messages();
+        assert_eq!(
+            replies[1],
+            (
+                addr(2),
+                Message::RequestVoteReply(RequestVoteReply {
+                    term: 1,
+                    vote_granted: false
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn test_split_vote() {
+        let mut nodes = cluster(2);
+        nodes[0].start_election();
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(nodes.iter().all(|n| !n.is_leader()));
+    }
+
+    #[test]
+    fn test_step_down_on_higher_term() {
+        let mut nodes = cluster(3);
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+
+        nodes[1].start_election();
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].status(), &Status::Follower);
+        assert!(nodes[1].is_leader());
+        assert_eq!(nodes[0].current_term(), 3);
+    }
+
+    /// Propose the data, returning the index of the new entry
+    /// if the node accepted it.
+    fn propose(node: &mut Node<Recorder>, data: &[u8]) -> Option<Index> {
+        let last_log_index = node.last_log_index();
+        drop(node.propose(data.to_vec()));
+        (node.last_log_index() > last_log_index).then_some(last_log_index + 1)
+    }
+
+    /// Elect node 1 as the leader of the cluster.
+    fn elect(nodes: &mut [Node<Recorder>]) {
+        nodes[0].start_election();
+        deliver(nodes);
+        assert!(nodes[0].is_leader());
+    }
+
+    #[test]
+    fn test_propose_not_leader() {
+        let mut nodes = cluster(3);
+        assert_eq!(propose(&mut nodes[0], b"x"), None);
+        assert!(nodes[0].entries().is_empty());
+    }
+
+    #[test]
+    fn test_replicate_and_commit() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert_eq!(nodes[1].leader(), Some(addr(1)));
+        // The leader committed a no-op entry when elected.
+        assert_eq!(nodes[0].entries()[0].kind, EntryKind::Noop);
+        assert_eq!(nodes[0].commit_index(), 1);
+
+        assert_eq!(propose(&mut nodes[0], b"x"), Some(2));
+        assert_eq!(propose(&mut nodes[0], b"y"), Some(3));
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit_index(), 3);
+        for node in &nodes[1..] {
+            assert_eq!(node.entries(), nodes[0].entries());
+        }
+
+        // Followers learn the commit index with the next AppendEntries.
+        nodes[0].replicate();
+        deliver(&mut nodes);
+        assert!(nodes.iter().all(|n| n.commit_index() == 3));
+    }
+
+    #[test]
+    fn test_commit_needs_majority() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        // Drop all the AppendEntries sent to the followers.
+        nodes[0].take_messages();
+        assert_eq!(nodes[0].commit_index(), 1);
+
+        nodes[0].replicate();
+        deliver(&mut nodes[..2]);
+        assert_eq!(nodes[0].commit_index(), 2);
+    }
+
+    #[test]
+    fn test_single_node_commit() {
+        let mut node = node(1);
+        node.start_election();
+        assert_eq!(node.commit_index(), 1);
+        assert_eq!(propose(&mut node, b"x"), Some(2));
+        assert_eq!(node.commit_index(), 2);
+    }
+
+    #[test]
+    fn test_reject_mismatched_prev_log() {
+        let mut node = node(2).set_peers([addr(1)]);
+        node.step(
+            addr(1),
+            Message::AppendEntries(AppendEntriesArgs {
+                term: 1,
+                leader: addr(1),
+                prev_log_index: 1,
+                prev_log_term: 1,
+                entries: vec![Entry::new(1, 2, vec![])],
+                leader_commit: 0,
+                round: 0,
+            }),
+        );
+        assert!(node.entries().is_empty());
+        assert_eq!(
+            node.take_messages(),
+            vec![(
+                addr(1),
+                Message::AppendEntriesReply(AppendEntriesReply {
+                    term: 1,
+                    success: false,
+                    match_index: 0,
+                    round: 0,
+                    conflict_term: 0,
+                    conflict_index: 1,
+                })
+            )]
+        );
+    }
+
+    #[test]
+    fn test_truncate_conflicting_entries() {
+        let mut node = node(2).set_peers([addr(1), addr(3)]);
+        let append = |term, prev_log_index, prev_log_term, entries| {
+            Message::AppendEntries(AppendEntriesArgs {
+                term,
+                leader: addr(1),
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit: 0,
+                round: 0,
+            })
+        };
+        node.step(
+            addr(1),
+            append(
+                1,
+                0,
+                0,
+                vec![Entry::new(1, 1, vec![1]), Entry::new(1, 2, vec![2])],
+            ),
+        );
+        node.step(addr(3), append(2, 1, 1, vec![Entry::new(2, 2, vec![3])]));
+        assert_eq!(
+            node.entries(),
+            &[Entry::new(1, 1, vec![1]), Entry::new(2, 2, vec![3])]
+        );
+    }
+
+    #[test]
+    fn test_follower_catches_up() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        propose(&mut nodes[0], b"y");
+        // Node 3 misses both entries.
+        let messages = nodes[0].take_messages();
+        for (to, message) in messages {
+            if to == addr(2) {
+                nodes[1].step(addr(1), message);
+            }
+        }
+        deliver(&mut nodes);
+        assert_eq!(nodes[2].entries().len(), 1);
+
+        nodes[0].replicate();
+        deliver(&mut nodes);
+        assert_eq!(nodes[2].entries(), nodes[0].entries());
+    }
+
+    #[test]
+    fn test_reject_vote_for_stale_log() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        // Node 3 never received the entry, so it cannot win.
+        let mut stale = node(3).set_peers((1..=3).map(addr));
+        stale.start_election();
+        stale.start_election();
+        for (to, message) in stale.take_messages() {
+            let node = nodes.iter_mut().find(|n| *n.socket_addr() == to);
+            node.unwrap().step(addr(3), message);
+        }
+        for node in &mut nodes[..2] {
+            for (_, message) in node.take_messages() {
+                stale.step(*node.socket_addr(), message);
+            }
+        }
+        assert!(!stale.is_leader());
+    }
+
+    #[test]
+    fn test_no_commit_for_previous_term_entries() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        // Only node 2 receives the entry, and the reply is lost.
+        for (to, message) in nodes[0].take_messages() {
+            if to == addr(2) {
+                nodes[1].step(addr(1), message);
+            }
+        }
+        nodes[1].take_messages();
+
+        // Node 2 becomes leader in term 2.
+        nodes[1].start_election();
+        for (to, message) in nodes[1].take_messages() {
+            let node = nodes.iter_mut().find(|n| *n.socket_addr() == to);
+            node.unwrap().step(addr(2), message);
+        }
+        for i in [0, 2] {
+            for (_, message) in nodes[i].take_messages() {
+                nodes[1].step(addr(i as u16 + 1), message);
+            }
+        }
+        assert!(nodes[1].is_leader());
+
+        // The entry from term 1 is on a majority, but not committed by
+        // counting, as long as the no-op entry of term 2 is not.
+        for (to, message) in nodes[1].take_messages() {
+            if let (true, Message::AppendEntries(mut args)) =
+                (to == addr(1), message)
+            {
+                args.entries.retain(|entry| entry.index <= 2);
+                nodes[0].step(addr(2), Message::AppendEntries(args));
+            }
+        }
+        for (_, message) in nodes[0].take_messages() {
+            nodes[1].step(addr(1), message);
+        }
+        assert_eq!(nodes[1].match_index(&addr(1)), Some(2));
+        assert_eq!(nodes[1].commit_index(), 1);
+
+        // Committed along with the no-op entry of the current term.
+        nodes[1].replicate();
+        deliver(&mut nodes);
+        assert_eq!(nodes[1].commit_index(), 3);
+    }
+
+    #[test]
+    fn test_apply_committed() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        propose(&mut nodes[0], b"y");
+        deliver(&mut nodes);
+        // The no-op entry is not applied to the state machine.
+        assert_eq!(nodes[0].last_applied(), 3);
+        assert_eq!(
+            nodes[0].state_machine().applied,
+            vec![b"x".to_vec(), b"y".to_vec()]
+        );
+
+        nodes[0].replicate();
+        deliver(&mut nodes);
+        for node in &nodes {
+            assert_eq!(node.last_applied(), 3);
+            assert_eq!(node.state_machine().applied.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_restore_hard_state() {
+        let dir = std::env::temp_dir()
+            .join(format!("raft_node_restore_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut node = Node::open(addr(3), Recorder::default(), &dir)
+            .unwrap()
+            .set_peers((1..=3).map(addr));
+        let vote = |candidate: u16| {
+            Message::RequestVote(RequestVoteArgs {
+                term: 1,
+                candidate: addr(candidate),
+                last_log_index: 0,
+                last_log_term: 0,
+                leadership_transfer: false,
+            })
+        };
+        node.step(addr(1), vote(1));
+        assert_eq!(node.voted_for(), Some(addr(1)));
+        drop(node);
+
+        // After a restart, the vote of term 1 is not given away again.
+        let mut node = Node::open(addr(3), Recorder::default(), &dir).unwrap();
+        assert_eq!(node.current_term(), 1);
+        assert_eq!(node.voted_for(), Some(addr(1)));
+        node.step(addr(2), vote(2));
+        assert_eq!(
+            node.take_messages(),
+            vec![(
+                addr(2),
+                Message::RequestVoteReply(RequestVoteReply {
+                    term: 1,
+                    vote_granted: false
+                })
+            )]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_log() {
+        let dir = std::env::temp_dir()
+            .join(format!("raft_node_restore_log_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut node = Node::open(addr(1), Recorder::default(), &dir).unwrap();
+        node.start_election();
+        propose(&mut node, b"x");
+        propose(&mut node, b"y");
+        assert_eq!(node.commit_index(), 3);
+        drop(node);
+
+        // Committed entries are applied again after a restart.
+        let node = Node::open(addr(1), Recorder::default(), &dir).unwrap();
+        assert_eq!(node.last_log_index(), 3);
+        assert_eq!(node.last_log_term(), 1);
+        assert_eq!(node.entries()[0].kind, EntryKind::Noop);
+        assert_eq!(node.last_applied(), 3);
+        assert_eq!(
+            node.state_machine().applied,
+            vec![b"x".to_vec(), b"y".to_vec()]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let mut nodes = cluster(3);
+        let start = tokio::time::Instant::now();
+        nodes[0].timeout().await;
+        assert_eq!(start.elapsed(), nodes[0].election_timer().timeout());
+
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        assert!(nodes[0].election_timer().is_cancelled());
+        let timeout = tokio::time::Duration::from_secs(10);
+        assert!(tokio::time::timeout(timeout, nodes[0].timeout())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_resets_timeout() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        let deadline = nodes[1].election_timer().deadline().unwrap();
+
+        // Longer than the spread of the random timeout.
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        nodes[0].replicate();
+        deliver(&mut nodes);
+        assert!(nodes[1].election_timer().deadline().unwrap() > deadline);
+
+        // A stale leader does not reset the timer.
+        let deadline = nodes[1].election_timer().deadline().unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        nodes[1].step(
+            addr(3),
+            Message::AppendEntries(AppendEntriesArgs {
+                term: 0,
+                leader: addr(3),
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![],
+                leader_commit: 0,
+                round: 0,
+            }),
+        );
+        assert_eq!(nodes[1].election_timer().deadline().unwrap(), deadline);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_does_not_block() {
+        let node = node(1);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move { tx.send(()).await });
+
+        // The message arrives while waiting for the timeout.
+        tokio::select! {
+            _ = node.timeout() => panic!("Timed out before the message"),
+            Some(()) = rx.recv() => {}
+        }
+    }
+
+    #[test]
+    fn test_set_config() {
+        let config = Config::new()
+            .set_election_timeout(1000..=2000)
+            .set_heartbeat_interval(200);
+        let node = node(1).set_config(config.clone()).unwrap();
+        assert_eq!(node.config(), &config);
+        assert!(node.election_timer().timeout().as_millis() >= 1000);
+
+        let config = Config::new().set_heartbeat_interval(100);
+        assert!(node.set_config(config).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let config = Config::new().set_heartbeat_interval(20);
+        let mut nodes = cluster_with(3, config);
+        let timeout = tokio::time::Duration::from_secs(10);
+        // Followers never send heartbeats.
+        assert!(tokio::time::timeout(timeout, nodes[0].heartbeat())
+            .await
+            .is_err());
+
+        elect(&mut nodes);
+        let start = tokio::time::Instant::now();
+        nodes[0].heartbeat().await;
+        assert_eq!(start.elapsed().as_millis(), 20);
+        nodes[0].send_heartbeat();
+        let messages = nodes[0].take_messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|(_, message)| matches!(
+            message,
+            Message::AppendEntries(AppendEntriesArgs { entries, .. })
+                if entries.is_empty()
+        )));
+
+        // The heartbeat stops when the leader steps down.
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(!nodes[0].is_leader());
+        assert!(tokio::time::timeout(timeout, nodes[0].heartbeat())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_keep_leader() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        // Run the cluster for a while, the leader never changes.
+        let end =
+            tokio::time::Instant::now() + tokio::time::Duration::from_secs(5);
+        while tokio::time::Instant::now() < end {
+            let (first, rest) = nodes.split_at_mut(1);
+            tokio::select! {
+                _ = first[0].heartbeat() => first[0].send_heartbeat(),
+                _ = rest[0].timeout() => rest[0].start_election(),
+                _ = rest[1].timeout() => rest[1].start_election(),
+            }
+            deliver(&mut nodes);
+        }
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 1);
+    }
+
+    #[test]
+    fn test_reject_stale_vote_reply() {
+        let mut node = node(1).set_peers([addr(2), addr(3)]);
+        node.start_election();
+        node.start_election();
+        node.step(
+            addr(2),
+            Message::RequestVoteReply(RequestVoteReply {
+                term: 1,
+                vote_granted: true,
+            }),
+        );
+        assert_eq!(node.status(), &Status::Candidate);
+    }
+
+    #[test]
+    fn test_take_snapshot() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        for data in [b"x", b"y", b"z"] {
+            propose(&mut nodes[0], data);
+        }
+        deliver(&mut nodes);
+        nodes[0].take_snapshot().unwrap();
+
+        let snapshot = nodes[0].snapshot();
+        assert_eq!(snapshot.last_included_index, 4);
+        assert_eq!(snapshot.last_included_term, 1);
+        assert!(nodes[0].entries().is_empty());
+        assert_eq!(nodes[0].last_log_index(), 4);
+        assert_eq!(nodes[0].last_log_term(), 1);
+
+        // Replication goes on after the compacted prefix.
+        propose(&mut nodes[0], b"w");
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].entries().len(), 1);
+        assert_eq!(nodes[0].commit_index(), 5);
+    }
+
+    #[test]
+    fn test_snapshot_threshold() {
+        let config = Config::new().set_snapshot_threshold(2);
+        let mut node = node(1).set_config(config).unwrap();
+        // The no-op entry of the election counts as applied.
+        node.start_election();
+        assert_eq!(node.snapshot().last_included_index, 0);
+        propose(&mut node, b"x");
+        assert_eq!(node.snapshot().last_included_index, 2);
+        assert!(node.entries().is_empty());
+        propose(&mut node, b"y");
+        assert_eq!(node.snapshot().last_included_index, 2);
+        assert_eq!(node.entries().len(), 1);
+    }
+
+    #[test]
+    fn test_install_snapshot() {
+        let config = Config::new().set_snapshot_chunk_size(3);
+        let mut nodes = cluster_with(3, config);
+        elect(&mut nodes);
+        // Node 3 misses all the entries compacted by the leader.
+        let mut lagging = nodes.pop().unwrap();
+        for data in [b"abc", b"def", b"ghi"] {
+            propose(&mut nodes[0], data);
+        }
+        deliver(&mut nodes);
+        nodes[0].take_snapshot().unwrap();
+        propose(&mut nodes[0], b"jkl");
+        deliver(&mut nodes);
+        lagging.take_messages();
+        nodes.push(lagging);
+
+        // The snapshot is sent in chunks of 3 bytes.
+        nodes[0].replicate();
+        let mut chunks = 0;
+        loop {
+            let messages = nodes[0].take_messages();
+            if messages.is_empty() {
+                break;
+            }
+            for (to, message) in messages {
+                if let Message::InstallSnapshot(args) = &message {
+                    assert!(args.data.len() <= 3);
+                    chunks += 1;
+                }
+                let node = nodes.iter_mut().find(|n| *n.socket_addr() == to);
+                node.unwrap().step(addr(1), message);
+            }
+            let (leader, followers) = nodes.split_at_mut(1);
+            for node in followers {
+                for (_, message) in node.take_messages() {
+                    leader[0].step(*node.socket_addr(), message);
+                }
+            }
+        }
+        // 3 entries of 3 bytes, each with a length prefix of 4 bytes.
+        assert_eq!(chunks, 7);
+        assert_eq!(nodes[2].snapshot(), nodes[0].snapshot());
+        assert_eq!(nodes[2].entries(), nodes[0].entries());
+        assert_eq!(nodes[2].commit_index(), 5);
+        assert_eq!(
+            nodes[2].state_machine().applied,
+            nodes[0].state_machine().applied
+        );
+    }
+
+    #[test]
+    fn test_single_snapshot_chunk_in_flight() {
+        let config = Config::new().set_snapshot_chunk_size(10);
+        let mut nodes = cluster_with(3, config);
+        elect(&mut nodes);
+        let mut lagging = nodes.pop().unwrap();
+        for _ in 0..10 {
+            propose(&mut nodes[0], b"abcdef");
+        }
+        deliver(&mut nodes);
+        nodes[0].take_snapshot().unwrap();
+        lagging.take_messages();
+        nodes.push(lagging);
+        let len = nodes[0].snapshot().data.len();
+        assert_eq!(len, 100);
+
+        // Heartbeats and proposals during the transfer
+        // send no extra chunk.
+        let mut chunks = 0;
+        for _ in 0..30 {
+            nodes[0].replicate();
+            propose(&mut nodes[0], b"x");
+            for (to, message) in nodes[0].take_messages() {
+                if matches!(message, Message::InstallSnapshot(_)) {
+                    chunks += 1;
+                }
+                let node = nodes.iter_mut().find(|n| *n.socket_addr() == to);
+                node.unwrap().step(addr(1), message);
+            }
+            let (leader, followers) = nodes.split_at_mut(1);
+            for node in followers {
+                for (_, message) in node.take_messages() {
+                    leader[0].step(*node.socket_addr(), message);
+                }
+            }
+        }
+        assert_eq!(chunks, len / 10);
+        assert_eq!(nodes[2].snapshot(), nodes[0].snapshot());
+        deliver(&mut nodes);
+        assert_eq!(nodes[2].entries(), nodes[0].entries());
+    }
+
+    #[test]
+    fn test_lost_snapshot_chunk_sent_again() {
+        let config = Config::new()
+            .set_election_timeout(30..=60)
+            .set_heartbeat_interval(10)
+            .set_snapshot_chunk_size(10);
+        let mut nodes = cluster_with(3, config);
+        elect(&mut nodes);
+        let mut lagging = nodes.pop().unwrap();
+        propose(&mut nodes[0], b"abcdefghijklmnop");
+        deliver(&mut nodes);
+        nodes[0].take_snapshot().unwrap();
+        lagging.take_messages();
+        nodes.push(lagging);
+        let snapshot_chunks = |node: &mut Node<Recorder>| {
+            let messages = node.take_messages().into_iter();
+            messages
+                .filter_map(|(_, message)| match message {
+                    Message::InstallSnapshot(args) => Some(args.offset),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The heartbeat is rejected by the lagging node, and the first
+        // chunk sent in return is lost. Heartbeats do not send it again.
+        nodes[0].replicate();
+        for (to, message) in nodes[0].take_messages() {
+            if to == addr(3) {
+                nodes[2].step(addr(1), message);
+            }
+        }
+        for (_, message) in nodes[2].take_messages() {
+            nodes[0].step(addr(3), message);
+        }
+        assert_eq!(snapshot_chunks(&mut nodes[0]), vec![0]);
+        nodes[0].replicate();
+        assert!(snapshot_chunks(&mut nodes[0]).is_empty());
+
+        // Until the minimum election timeout has passed.
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        nodes[0].replicate();
+        assert_eq!(snapshot_chunks(&mut nodes[0]), vec![0]);
+    }
+
+    #[test]
+    fn test_out_of_order_snapshot_chunk() {
+        let mut node = node(2).set_peers([addr(1)]);
+        let chunk = |offset: u64, data: &[u8], done: bool| {
+            Message::InstallSnapshot(InstallSnapshotArgs {
+                term: 1,
+                leader: addr(1),
+                last_included_index: 5,
+                last_included_term: 1,
+                membership: Membership::new([addr(1), addr(2)]),
+                offset,
+                data: data.to_vec(),
+                done,
+            })
+        };
+        node.step(addr(1), chunk(0, b"ab", false));
+        node.step(addr(1), chunk(4, b"ef", true));
+        let reply = |chunk_offset: u64, offset: u64, done: bool| {
+            Message::InstallSnapshotReply(InstallSnapshotReply {
+                term: 1,
+                last_included_index: 5,
+                chunk_offset,
+                offset,
+                done,
+            })
+        };
+        assert_eq!(
+            node.take_messages(),
+            vec![(addr(1), reply(0, 2, false)), (addr(1), reply(4, 2, false))]
+        );
+
+        // A duplicate of the first chunk keeps the chunks received.
+        node.step(addr(1), chunk(0, b"ab", false));
+        node.step(addr(1), chunk(2, b"cd", false));
+        assert_eq!(
+            node.take_messages(),
+            vec![(addr(1), reply(0, 2, false)), (addr(1), reply(2, 4, false))]
+        );
+        assert_eq!(node.snapshot().last_included_index, 0);
+    }
+
+    #[test]
+    fn test_restore_snapshot() {
+        let dir = std::env::temp_dir()
+            .join(format!("raft_node_snapshot_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut node = Node::open(addr(1), Recorder::default(), &dir).unwrap();
+        node.start_election();
+        propose(&mut node, b"x");
+        propose(&mut node, b"y");
+        node.take_snapshot().unwrap();
+        propose(&mut node, b"z");
+        drop(node);
+
+        // The state machine is restored from the snapshot,
+        // and the entry that follows it is applied again.
+        let node = Node::open(addr(1), Recorder::default(), &dir).unwrap();
+        assert_eq!(node.snapshot().last_included_index, 3);
+        assert_eq!(node.entries().len(), 1);
+        assert_eq!(node.last_applied(), 4);
+        assert_eq!(
+            node.state_machine().applied,
+            vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec()]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pre_vote_election() {
+        let mut nodes = cluster_with(3, Config::new().set_pre_vote(true));
+        nodes[0].start_election();
+        assert_eq!(nodes[0].status(), &Status::PreCandidate);
+        assert_eq!(nodes[0].current_term(), 0);
+        for (_, message) in nodes[0].take_messages() {
+            assert!(matches!(message, Message::PreVote(ref a) if a.term == 1));
+            nodes[1].step(addr(1), message);
+        }
+        // Granting a Pre-Vote changes nothing on the voter.
+        assert_eq!(nodes[1].current_term(), 0);
+        assert_eq!(nodes[1].voted_for(), None);
+
+        for (_, message) in nodes[1].take_messages() {
+            nodes[0].step(addr(2), message);
+        }
+        assert_eq!(nodes[0].status(), &Status::Candidate);
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pre_vote_partitioned_node() {
+        let mut nodes = cluster_with(3, Config::new().set_pre_vote(true));
+        elect(&mut nodes);
+
+        // Node 3 is partitioned and keeps timing out, without
+        // ever incrementing its term.
+        for _ in 0..3 {
+            nodes[2].start_election();
+            nodes[2].take_messages();
+        }
+        assert_eq!(nodes[2].status(), &Status::PreCandidate);
+        assert_eq!(nodes[2].current_term(), 1);
+
+        // Once back, it is refused while the leader is alive,
+        // and the leader stays in place.
+        nodes[2].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 1);
+
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes);
+        assert_eq!(nodes[2].status(), &Status::Follower);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pre_vote_after_leader_failure() {
+        let mut nodes = cluster_with(3, Config::new().set_pre_vote(true));
+        elect(&mut nodes);
+        nodes.remove(0);
+
+        // Refused until the followers stop hearing from the leader.
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].status(), &Status::PreCandidate);
+
+        tokio::time::advance(Duration::from_millis(150)).await;
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_quorum_step_down() {
+        let mut nodes = cluster_with(3, Config::new().set_check_quorum(true));
+        elect(&mut nodes);
+
+        // The followers replied during the first election timeout.
+        nodes[0].timeout().await;
+        nodes[0].start_election();
+        assert!(nodes[0].is_leader());
+
+        // Then the leader is partitioned from both of them.
+        nodes[0].send_heartbeat();
+        nodes[0].take_messages();
+        nodes[0].timeout().await;
+        nodes[0].start_election();
+        assert_eq!(nodes[0].status(), &Status::Follower);
+        assert_eq!(nodes[0].leader(), None);
+        assert_eq!(propose(&mut nodes[0], b"x"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_quorum_rejoining_node() {
+        let mut nodes = cluster_with(3, Config::new().set_check_quorum(true));
+        elect(&mut nodes);
+
+        // Node 3 is partitioned and keeps timing out.
+        for _ in 0..3 {
+            nodes[2].start_election();
+            nodes[2].take_messages();
+        }
+        assert_eq!(nodes[2].current_term(), 4);
+
+        // Once back, its election does not depose the leader.
+        nodes[2].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 1);
+        assert_eq!(nodes[1].current_term(), 1);
+        assert_eq!(nodes[1].leader(), Some(addr(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_quorum_keeps_leader() {
+        let mut nodes = cluster_with(3, Config::new().set_check_quorum(true));
+        elect(&mut nodes);
+        // Node 3 is down, but the leader still hears from node 2.
+        let end =
+            tokio::time::Instant::now() + tokio::time::Duration::from_secs(5);
+        while tokio::time::Instant::now() < end {
+            let (first, rest) = nodes.split_at_mut(1);
+            tokio::select! {
+                _ = first[0].heartbeat() => first[0].send_heartbeat(),
+                _ = first[0].timeout() => first[0].start_election(),
+                _ = rest[0].timeout() => rest[0].start_election(),
+            }
+            deliver(&mut nodes[..2]);
+        }
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].current_term(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_index() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        let read = nodes[0].read_index();
+        tokio::pin!(read);
+        // Not ready until a majority acknowledged the heartbeat round.
+        let wait = tokio::time::Duration::from_millis(1);
+        assert!(tokio::time::timeout(wait, &mut read).await.is_err());
+        deliver(&mut nodes);
+        assert_eq!(read.await, Ok(2));
+        assert_eq!(nodes[0].state_machine().applied, vec![b"x".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_read_index_not_leader() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert_eq!(
+            nodes[1].read_index().await,
+            Err(ReadError::NotLeader { leader_hint: Some(addr(1)) })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_index_waits_for_current_term() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        // Only node 2 receives the entry, and the reply is lost.
+        for (to, message) in nodes[0].take_messages() {
+            if to == addr(2) {
+                nodes[1].step(addr(1), message);
+            }
+        }
+        nodes[1].take_messages();
+        // Node 2 is elected with the vote of node 3 alone.
+        nodes[1].start_election();
+        for (to, message) in nodes[1].take_messages() {
+            if to == addr(3) {
+                nodes[2].step(addr(2), message);
+            }
+        }
+        for (_, message) in nodes[2].take_messages() {
+            nodes[1].step(addr(3), message);
+        }
+        assert!(nodes[1].is_leader());
+
+        // The entry from term 1 may be committed,
+        // so the read waits for the no-op entry of term 2.
+        let read = nodes[1].read_index();
+        tokio::pin!(read);
+        let wait = tokio::time::Duration::from_millis(1);
+        assert!(tokio::time::timeout(wait, &mut read).await.is_err());
+
+        deliver(&mut nodes);
+        assert_eq!(read.await, Ok(3));
+        assert_eq!(nodes[1].state_machine().applied, vec![b"x".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_read_index_leadership_lost() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        let read = nodes[0].read_index();
+        nodes[0].take_messages();
+        nodes[0].step(
+            addr(2),
+            Message::RequestVoteReply(RequestVoteReply {
+                term: 2,
+                vote_granted: false,
+            }),
+        );
+        assert_eq!(read.await, Err(ReadError::LeadershipLost));
+    }
+
+    #[tokio::test]
+    async fn test_read_index_single_node() {
+        let mut node = node(1);
+        node.start_election();
+        propose(&mut node, b"x");
+        assert_eq!(node.read_index().await, Ok(2));
+    }
+
+    /// Get a configuration serving reads under a lease.
+    fn lease_config() -> Config {
+        Config::new().set_read_mode(ReadMode::Lease { clock_drift: 20 })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_read() {
+        let mut nodes = cluster_with(3, lease_config());
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        // Served locally while the lease runs.
+        let read = nodes[0].read_index();
+        assert!(nodes[0].take_messages().is_empty());
+        assert_eq!(read.await, Ok(2));
+
+        // Back to a heartbeat round once it expired.
+        tokio::time::advance(Duration::from_millis(130)).await;
+        let read = nodes[0].read_index();
+        assert!(!nodes[0].take_messages().is_empty());
+        drop(read);
+
+        // A heartbeat acknowledged by a majority renews the lease.
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes);
+        let read = nodes[0].read_index();
+        assert!(nodes[0].take_messages().is_empty());
+        assert_eq!(read.await, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_refuse_vote() {
+        let mut nodes = cluster_with(3, lease_config());
+        elect(&mut nodes);
+
+        // Node 3 cannot be elected while node 2 hears from the leader.
+        let vote = Message::RequestVote(RequestVoteArgs {
+            term: 2,
+            candidate: addr(3),
+            last_log_index: 1,
+            last_log_term: 1,
+            leadership_transfer: false,
+        });
+        nodes[1].step(addr(3), vote.clone());
+        assert!(nodes[1].take_messages().is_empty());
+        assert_eq!(nodes[1].current_term(), 1);
+
+        tokio::time::advance(Duration::from_millis(150)).await;
+        nodes[1].step(addr(3), vote);
+        assert_eq!(nodes[1].voted_for(), Some(addr(3)));
+    }
+
+    #[test]
+    fn test_transfer_leadership() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        nodes[0].transfer_leadership(addr(2)).unwrap();
+        assert_eq!(nodes[0].transferee(), Some(addr(2)));
+        assert_eq!(propose(&mut nodes[0], b"y"), None);
+        deliver(&mut nodes);
+        assert!(nodes[1].is_leader());
+        assert_eq!(nodes[1].current_term(), 2);
+        assert_eq!(nodes[0].status(), &Status::Follower);
+        assert_eq!(nodes[0].transferee(), None);
+    }
+
+    #[test]
+    fn test_transfer_leadership_to_lagging_peer() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        // Node 3 misses the entry.
+        for (to, message) in nodes[0].take_messages() {
+            if to == addr(2) {
+                nodes[1].step(addr(1), message);
+            }
+        }
+        deliver(&mut nodes);
+
+        // Node 3 is brought up to date before taking over,
+        // otherwise it could not win the election.
+        nodes[0].transfer_leadership(addr(3)).unwrap();
+        let messages = nodes[0].take_messages();
+        assert!(matches!(messages[..], [(_, Message::AppendEntries(_))]));
+        for (_, message) in messages {
+            nodes[2].step(addr(1), message);
+        }
+        deliver(&mut nodes);
+        assert!(nodes[2].is_leader());
+        assert_eq!(nodes[2].entries()[1].data, b"x");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transfer_leadership_timeout() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        nodes[0].transfer_leadership(addr(2)).unwrap();
+        nodes[0].take_messages();
+        assert_eq!(propose(&mut nodes[0], b"x"), None);
+
+        // Node 2 never takes over, so the leader goes on.
+        tokio::time::advance(Duration::from_millis(150)).await;
+        nodes[0].send_heartbeat();
+        assert_eq!(nodes[0].transferee(), None);
+        assert_eq!(propose(&mut nodes[0], b"x"), Some(2));
+    }
+
+    #[test]
+    fn test_transfer_leadership_errors() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert_eq!(
+            nodes[1].transfer_leadership(addr(3)),
+            Err(TransferError::NotLeader { leader_hint: Some(addr(1)) })
+        );
+        assert_eq!(
+            nodes[0].transfer_leadership(addr(4)),
+            Err(TransferError::UnknownTarget(addr(4)))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transfer_leadership_under_lease() {
+        let mut nodes = cluster_with(3, lease_config());
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        // The voters hear from the leader, but the leader asked for it.
+        nodes[0].transfer_leadership(addr(3)).unwrap();
+        deliver(&mut nodes);
+        assert!(nodes[2].is_leader());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_lease_during_transfer() {
+        let mut nodes = cluster_with(3, lease_config());
+        elect(&mut nodes);
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes);
+        let read = nodes[0].read_index();
+        assert!(nodes[0].take_messages().is_empty());
+        assert_eq!(read.await, Ok(1));
+
+        // Node 3 may be elected at once, so the rounds acknowledged
+        // during the transfer do not extend the lease.
+        nodes[0].transfer_leadership(addr(3)).unwrap();
+        nodes[0].take_messages();
+        tokio::time::advance(Duration::from_millis(140)).await;
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes[..2]);
+
+        // Still no lease once the transfer is abandoned.
+        tokio::time::advance(Duration::from_millis(20)).await;
+        nodes[0].send_heartbeat();
+        assert_eq!(nodes[0].transferee(), None);
+        nodes[0].take_messages();
+        let read = nodes[0].read_index();
+        assert!(!nodes[0].take_messages().is_empty());
+        drop(read);
+    }
+
+    #[test]
+    fn test_add_voter() {
+        let mut nodes = cluster(3);
+        nodes.push(node(4).set_membership(Membership::default()));
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+
+        let index = nodes[0].change_membership((1..=4).map(addr)).unwrap();
+        assert!(nodes[0].membership().is_joint());
+        deliver(&mut nodes);
+
+        // The joint membership is committed, then the new one.
+        assert_eq!(nodes[0].commit_index(), index + 1);
+        for node in &nodes {
+            assert_eq!(node.membership(), &Membership::new((1..=4).map(addr)));
+            assert_eq!(node.last_log_index(), index + 1);
+        }
+        assert_eq!(nodes[3].state_machine().applied, vec![b"x".to_vec()]);
+    }
+
+    #[test]
+    fn test_remove_leader() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        nodes[0].change_membership([addr(2), addr(3)]).unwrap();
+        deliver(&mut nodes);
+
+        // The leader steps down once the new membership is committed.
+        assert!(!nodes[0].is_leader());
+        assert_eq!(nodes[1].membership(), &Membership::new([addr(2), addr(3)]));
+
+        // The removed node no longer starts elections.
+        nodes[0].start_election();
+        assert!(nodes[0].take_messages().is_empty());
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[1].is_leader());
+        assert_eq!(nodes[1].peers(), &[addr(3)]);
+    }
+
+    #[test]
+    fn test_remove_follower() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert!(nodes[0].acked_round.contains_key(&addr(3)));
+        nodes[0].change_membership([addr(1), addr(2)]).unwrap();
+        deliver(&mut nodes);
+
+        // Nothing is left of the removed peer on the leader.
+        assert!(nodes[0].is_leader());
+        assert_eq!(nodes[0].peers(), &[addr(2)]);
+        assert!(!nodes[0].acked_round.contains_key(&addr(3)));
+        assert!(!nodes[0].recent_active.contains(&addr(3)));
+        assert!(!nodes[0].match_index.contains_key(&addr(3)));
+        assert!(!nodes[0].snapshot_transfers.contains_key(&addr(3)));
+    }
+
+    #[test]
+    fn test_joint_membership_needs_both_majorities() {
+        let mut nodes = cluster(3);
+        nodes.push(node(4).set_membership(Membership::default()));
+        nodes.push(node(5).set_membership(Membership::default()));
+        elect(&mut nodes);
+        let index = nodes[0].change_membership([1, 4, 5].map(addr)).unwrap();
+
+        // The old majority alone cannot commit the joint membership.
+        let mut old = nodes.split_off(3);
+        deliver(&mut nodes);
+        assert!(nodes[0].commit_index() < index);
+
+        // It commits once the new voters replicate it too.
+        nodes.append(&mut old);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+        assert_eq!(
+            nodes[0].membership(),
+            &Membership::new([1, 4, 5].map(addr))
+        );
+        assert_eq!(nodes[0].state_machine().applied, vec![b"x".to_vec()]);
+    }
+
+    #[test]
+    fn test_new_leader_finishes_membership_change() {
+        let mut nodes = cluster(3);
+        nodes.push(node(4).set_membership(Membership::default()));
+        elect(&mut nodes);
+        nodes[0].change_membership((1..=4).map(addr)).unwrap();
+        // The followers receive the joint membership, and node 1 fails
+        // before committing it.
+        for (to, message) in nodes[0].take_messages() {
+            if let Some(node) =
+                nodes.iter_mut().find(|n| *n.socket_addr() == to)
+            {
+                node.step(addr(1), message);
+            }
+        }
+        let mut nodes = nodes.split_off(1);
+        for node in &mut nodes {
+            node.take_messages();
+        }
+        assert!(nodes[0].membership().is_joint());
+
+        // Node 2 takes over, and completes the change without any command.
+        nodes[0].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[0].is_leader());
+        for node in &nodes {
+            assert_eq!(node.membership(), &Membership::new((1..=4).map(addr)));
+        }
+        assert_eq!(nodes[0].commit_index(), nodes[0].last_log_index());
+        assert!(nodes[0].state_machine().applied.is_empty());
+    }
+
+    #[test]
+    fn test_change_membership_errors() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert_eq!(
+            nodes[1].change_membership([addr(1)]),
+            Err(MembershipError::NotLeader { leader_hint: Some(addr(1)) })
+        );
+        assert_eq!(
+            nodes[0].change_membership([]),
+            Err(MembershipError::NoVoters)
+        );
+        nodes[0].change_membership([addr(1), addr(2)]).unwrap();
+        assert_eq!(
+            nodes[0].change_membership([addr(1)]),
+            Err(MembershipError::InProgress)
+        );
+    }
+
+    #[test]
+    fn test_truncated_membership_reverted() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        nodes[0].change_membership([addr(1), addr(2)]).unwrap();
+        nodes[0].take_messages();
+        assert!(nodes[0].membership().is_joint());
+
+        // Node 2 wins the next election and overwrites the entry.
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[1].is_leader());
+        propose(&mut nodes[1], b"x");
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].membership(), &Membership::new((1..=3).map(addr)));
+        assert_eq!(nodes[0].last_log_term(), 2);
+    }
+
+    #[test]
+    fn test_restore_membership() {
+        let dir = std::env::temp_dir()
+            .join(format!("raft_node_membership_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut node = Node::open(addr(1), Recorder::default(), &dir).unwrap();
+        node.start_election();
+        propose(&mut node, b"x");
+        node.take_snapshot().unwrap();
+        node.change_membership([addr(1), addr(2)]).unwrap();
+        drop(node);
+
+        // The change in the log takes precedence over the snapshot,
+        // and both over the initial membership.
+        let node = Node::open(addr(1), Recorder::default(), &dir)
+            .unwrap()
+            .set_peers([addr(3)]);
+        assert_eq!(node.snapshot().membership, Membership::new([addr(1)]));
+        assert!(node.membership().is_joint());
+        assert_eq!(node.peers(), &[addr(2)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_learner() {
+        let mut nodes = cluster(3);
+        nodes.push(node(4).set_membership(Membership::default()));
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"x");
+        deliver(&mut nodes);
+        let index = nodes[0].add_learner(addr(4)).unwrap();
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit_index(), index);
+        assert!(nodes[3].membership().is_learner(&addr(4)));
+        assert_eq!(nodes[0].match_index(&addr(4)), Some(index));
+
+        // The learner receives the log but does not count in the quorum.
+        let mut voters = nodes.split_off(1);
+        let mut learner = voters.split_off(2);
+        nodes.append(&mut learner);
+        propose(&mut nodes[0], b"y");
+        deliver(&mut nodes);
+        assert_eq!(nodes[1].last_log_index(), index + 1);
+        assert_eq!(nodes[0].commit_index(), index);
+
+        // Nor does it start elections.
+        nodes[1].start_election();
+        assert!(nodes[1].take_messages().is_empty());
+
+        // Once the voters are back, the entry is committed and applied
+        // by the learner with the next heartbeat.
+        nodes.append(&mut voters);
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit_index(), index + 1);
+        nodes[0].send_heartbeat();
+        deliver(&mut nodes);
+        assert_eq!(
+            nodes[1].state_machine().applied,
+            vec![b"x".to_vec(), b"y".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_promote_learner() {
+        let mut nodes = cluster(3);
+        nodes.push(node(4).set_membership(Membership::default()));
+        nodes.push(node(5).set_membership(Membership::default()));
+        elect(&mut nodes);
+        nodes[0].add_learner(addr(4)).unwrap();
+        deliver(&mut nodes);
+        nodes[0].add_learner(addr(5)).unwrap();
+        deliver(&mut nodes);
+
+        nodes[0].change_membership((1..=4).map(addr)).unwrap();
+        deliver(&mut nodes);
+        let membership =
+            Membership::new((1..=4).map(addr)).set_learners([addr(5)]);
+        for node in &nodes {
+            assert_eq!(node.membership(), &membership);
+        }
+        nodes[0].remove_learner(addr(5)).unwrap();
+        deliver(&mut nodes);
+        assert_eq!(nodes[1].membership(), &Membership::new((1..=4).map(addr)));
+        assert_eq!(nodes[0].peers().len(), 3);
+    }
+
+    #[test]
+    fn test_learner_errors() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        assert_eq!(
+            nodes[0].add_learner(addr(2)),
+            Err(MembershipError::AlreadyMember(addr(2)))
+        );
+        assert_eq!(
+            nodes[0].remove_learner(addr(4)),
+            Err(MembershipError::NotLearner(addr(4)))
+        );
+        nodes[0].add_learner(addr(4)).unwrap();
+        assert_eq!(
+            nodes[0].add_learner(addr(5)),
+            Err(MembershipError::InProgress)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_propose_resolves_on_apply() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        let applied = nodes[0].propose(b"x".to_vec());
+        deliver(&mut nodes);
+        assert_eq!(applied.await, Ok(b"x".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_propose_not_leader_hint() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        let applied = nodes[1].propose(b"x".to_vec());
+        assert_eq!(
+            applied.await,
+            Err(ProposeError::NotLeader { leader_hint: Some(addr(1)) })
+        );
+
+        // During a transfer, the node taking over is given as a hint.
+        nodes[0].transfer_leadership(addr(2)).unwrap();
+        let applied = nodes[0].propose(b"x".to_vec());
+        assert_eq!(
+            applied.await,
+            Err(ProposeError::NotLeader { leader_hint: Some(addr(2)) })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_propose_term_changed() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        let applied = nodes[0].propose(b"x".to_vec());
+        nodes[0].take_messages();
+
+        // Node 2 takes over before the entry is replicated.
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[1].is_leader());
+        assert_eq!(applied.await, Err(ProposeError::TermChanged));
+    }
+
+    /// Get the number of entries of each AppendEntries waiting to be sent
+    /// to the peer.
+    fn batches(node: &Node<Recorder>, peer: SocketAddr) -> Vec<usize> {
+        node.messages
+            .iter()
+            .filter_map(|(to, message)| match message {
+                Message::AppendEntries(args) if *to == peer => {
+                    Some(args.entries.len())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pipeline_append_entries() {
+        let config = Config::new().set_max_batch_size(2).set_max_inflight(2);
+        let mut nodes = cluster_with(3, config);
+        elect(&mut nodes);
+
+        // Entries are sent without waiting for the previous ones,
+        // until the window is full.
+        for data in [b"a", b"b", b"c", b"d", b"e"] {
+            propose(&mut nodes[0], data);
+        }
+        assert_eq!(batches(&nodes[0], addr(2)), [1, 1]);
+
+        // Entries proposed meanwhile are batched once the window frees up.
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit_index(), 6);
+        assert_eq!(nodes[2].entries(), nodes[0].entries());
+    }
+
+    #[test]
+    fn test_batch_while_window_full() {
+        let config = Config::new().set_max_batch_size(16).set_max_inflight(1);
+        let mut nodes = cluster_with(2, config);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"a");
+        let messages = nodes[0].take_messages();
+        propose(&mut nodes[0], b"b");
+        propose(&mut nodes[0], b"c");
+        assert!(batches(&nodes[0], addr(2)).is_empty());
+
+        for (_, message) in messages {
+            nodes[1].step(addr(1), message);
+        }
+        for (_, message) in nodes[1].take_messages() {
+            nodes[0].step(addr(2), message);
+        }
+        assert_eq!(batches(&nodes[0], addr(2)), [2]);
+    }
+
+    #[test]
+    fn test_pipeline_lost_entries() {
+        let config = Config::new().set_max_batch_size(1).set_max_inflight(4);
+        let mut nodes = cluster_with(3, config);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"a");
+        propose(&mut nodes[0], b"b");
+        assert_eq!(batches(&nodes[0], addr(3)), [1, 1]);
+        nodes[0].take_messages();
+
+        // The next heartbeat is rejected, and the entries sent again.
+        nodes[0].replicate();
+        deliver(&mut nodes);
+        assert_eq!(nodes[0].commit_index(), 3);
+        assert_eq!(nodes[2].entries(), nodes[0].entries());
+    }
+
+    #[test]
+    fn test_pipeline_lost_probe() {
+        let config = Config::new().set_max_batch_size(16).set_max_inflight(1);
+        let mut nodes = cluster_with(2, config);
+        elect(&mut nodes);
+        propose(&mut nodes[0], b"a");
+        nodes[0].take_messages();
+
+        // The heartbeat is rejected, and the entry sent again is lost.
+        nodes[0].send_heartbeat();
+        for (_, message) in nodes[0].take_messages() {
+            nodes[1].step(addr(1), message);
+        }
+        for (_, message) in nodes[1].take_messages() {
+            nodes[0].step(addr(2), message);
+        }
+        assert_eq!(batches(&nodes[0], addr(2)), [1]);
+        nodes[0].take_messages();
+
+        // The next heartbeat is accepted, and the entry sent once more.
+        for _ in 0..3 {
+            nodes[0].send_heartbeat();
+            deliver(&mut nodes);
+        }
+        assert_eq!(nodes[1].last_log_index(), nodes[0].last_log_index());
+        assert_eq!(nodes[0].commit_index(), 2);
+    }
+
+    /// Create a node with entries of the given terms in its log.
+    fn node_with_log(port: u16, terms: &[Term]) -> Node<Recorder> {
+        let mut log = MemStorage::new();
+        for (i, &term) in terms.iter().enumerate() {
+            log.append(&[Entry::new(term, i as Index + 1, vec![])]).unwrap();
+        }
+        node(port).set_log_storage(log)
+    }
+
+    #[test]
+    fn test_find_conflict() {
+        let node = node_with_log(2, &[1, 1, 2, 2, 2]);
+        assert_eq!(node.find_conflict(4), (2, 3));
+        assert_eq!(node.find_conflict(2), (1, 1));
+        assert_eq!(node.find_conflict(9), (0, 6));
+    }
+
+    #[test]
+    fn test_next_index_hint() {
+        let node = node_with_log(1, &[1, 1, 3, 3]);
+        let reply = |conflict_term, conflict_index| AppendEntriesReply {
+            term: 3,
+            success: false,
+            match_index: 0,
+            round: 0,
+            conflict_term,
+            conflict_index,
+        };
+        // The leader has no entry of term 2, so it skips the whole term.
+        assert_eq!(node.next_index_hint(&reply(2, 3)), 3);
+        // It resumes after its last entry of term 1.
+        assert_eq!(node.next_index_hint(&reply(1, 1)), 3);
+        assert_eq!(node.next_index_hint(&reply(0, 2)), 2);
+    }
+
+    #[test]
+    fn test_offline_follower_catches_up_fast() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes);
+        let mut offline = nodes.split_off(2);
+        for _ in 0..50 {
+            propose(&mut nodes[0], b"x");
+        }
+        deliver(&mut nodes);
+        nodes[1].start_election();
+        deliver(&mut nodes);
+        assert!(nodes[1].is_leader());
+        nodes.append(&mut offline);
+
+        // A single rejection tells the leader where the follower stands.
+        nodes[1].replicate();
+        for (to, message) in nodes[1].take_messages() {
+            if to == addr(3) {
+                nodes[2].step(addr(2), message);
+            }
+        }
+        let replies = nodes[2].take_messages();
+        assert!(matches!(
+            replies[..],
+            [(
+                _,
+                Message::AppendEntriesReply(AppendEntriesReply {
+                    success: false,
+                    conflict_term: 0,
+                    conflict_index: 2,
+                    ..
+                })
+            )]
+        ));
+        for (_, message) in replies {
+            nodes[1].step(addr(3), message);
+        }
+        let messages = nodes[1].take_messages();
+        assert!(matches!(
+            &messages[..],
+            [(_, Message::AppendEntries(args))] if args.prev_log_index == 1
+        ));
     }
 }
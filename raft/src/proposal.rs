@@ -0,0 +1,65 @@
+//! Writes proposed by clients to the leader.
+//!
+//! A proposal is appended to the log of the leader, and completes once
+//! the entry is committed and applied to the state machine, with the
+//! response of the state machine.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::sync::oneshot;
+
+use crate::state_machine::Response;
+use crate::Index;
+
+/// A proposal waiting for its entry to be applied.
+pub(crate) struct PendingProposal {
+    /// Index of the entry holding the proposal.
+    pub(crate) index: Index,
+
+    /// Where to send the response once the entry is applied.
+    pub(crate) ready: oneshot::Sender<Result<Response, ProposeError>>,
+}
+
+/// Reasons why a proposal did not complete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposeError {
+    /// The node is not the leader, or is handing over its leadership.
+    /// The leader, or the node taking over, is given if known, so that
+    /// the proposal can be retried there.
+    NotLeader { leader_hint: Option<SocketAddr> },
+
+    /// The term changed, or the node was shut down, before the entry
+    /// was applied. The entry may still be committed by the next leader,
+    /// so retrying the proposal may apply it twice, unless it is a
+    /// [`Command`] of a client session.
+    ///
+    /// [`Command`]: crate::session::Command
+    TermChanged,
+
+    /// The entry could not be written to the log.
+    Storage(io::ErrorKind),
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader { leader_hint: Some(leader) } => {
+                write!(f, "Not the leader, try {}", leader)
+            }
+            ProposeError::NotLeader { leader_hint: None } => {
+                write!(f, "Not the leader")
+            }
+            ProposeError::TermChanged => {
+                write!(f, "Term changed before the entry was applied")
+            }
+            ProposeError::Storage(kind) => {
+                write!(f, "Failed to append the entry: {}", kind)
+            }
+        }
+    }
+}
+
+impl Error for ProposeError {}
@@ -0,0 +1,59 @@
+//! Linearizable reads served by the leader without writing to the log.
+//!
+//! A read is served at a *read index*: the commit index of the leader
+//! when the read was requested. Once the leader has confirmed that it
+//! was still leading at that time, and has applied all the entries up
+//! to the read index, the state machine reflects every write completed
+//! before the read.
+
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+use tokio::sync::oneshot;
+
+use crate::Index;
+
+/// A read waiting to be served by the leader.
+pub(crate) struct PendingRead {
+    /// Commit index when the read was requested, `None` until the leader
+    /// has committed an entry of its own term.
+    pub(crate) index: Option<Index>,
+
+    /// Heartbeat round that must be acknowledged by a majority
+    /// to confirm the leadership.
+    pub(crate) round: u64,
+
+    /// Where to send the read index once the read can be served.
+    pub(crate) ready: oneshot::Sender<Result<Index, ReadError>>,
+}
+
+/// Reasons why a read cannot be served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadError {
+    /// The node is not the leader. The leader of the current term is
+    /// given if known, so that the read can be retried there.
+    NotLeader { leader_hint: Option<SocketAddr> },
+
+    /// The node stopped being the leader, or was shut down,
+    /// before the read could be served.
+    LeadershipLost,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::NotLeader { leader_hint: Some(leader) } => {
+                write!(f, "Not the leader, try {}", leader)
+            }
+            ReadError::NotLeader { leader_hint: None } => {
+                write!(f, "Not the leader")
+            }
+            ReadError::LeadershipLost => {
+                write!(f, "Leadership lost before the read was served")
+            }
+        }
+    }
+}
+
+impl Error for ReadError {}
@@ -0,0 +1,329 @@
+//! Client sessions, applying each command exactly once.
+//!
+//! A client retrying a proposal after a leader change cannot tell whether
+//! the first attempt was committed, so the command may end up twice in
+//! the log. Wrapping the state machine in [`Sessions`] makes it remember
+//! the last sequence number applied for each client, along with its
+//! response: a duplicate is not applied again, and gets the response of
+//! the first attempt instead.
+//!
+//! Each client picks a unique [`ClientId`], and numbers its commands
+//! from `1`, sending the next one only once the previous one completed.
+//!
+//! ``` rust
+//! use raft::entry::Entry;
+//! use raft::session::{Command, Sessions};
+//! use raft::state_machine::StateMachine;
+//!
+//! # #[derive(Default)]
+//! # struct Counter(u64);
+//! # impl StateMachine for Counter {
+//! #     fn apply(&mut self, _: &Entry) -> Vec<u8> {
+//! #         self.0 += 1;
+//! #         self.0.to_le_bytes().to_vec()
+//! #     }
+//! #     fn snapshot(&self) -> Vec<u8> { self.0.to_le_bytes().to_vec() }
+//! #     fn restore(&mut self, s: &[u8]) {
+//! #         self.0 = u64::from_le_bytes(s.try_into().unwrap());
+//! #     }
+//! # }
+//! let mut counter = Sessions::new(Counter::default());
+//! let data = Command::new(7, 1, b"incr".to_vec()).encode();
+//! let first = counter.apply(&Entry::new(1, 1, data.clone()));
+//! // The retry is not applied again.
+//! let retry = counter.apply(&Entry::new(2, 2, data));
+//! assert_eq!(first, retry);
+//! assert_eq!(counter.state_machine().0, 1);
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::entry::Entry;
+use crate::state_machine::{Response, StateMachine};
+
+/// Unique identifier of a client, chosen by the client.
+pub type ClientId = u64;
+
+/// A command for the state machine, within a client session or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// Client and sequence number of the command, `None` for a command
+    /// applied every time it is in the log.
+    pub session: Option<(ClientId, u64)>,
+
+    /// Command for the wrapped state machine.
+    pub data: Vec<u8>,
+}
+
+impl Command {
+    /// Create a command of the given client, with its sequence number.
+    pub fn new(client: ClientId, sequence: u64, data: Vec<u8>) -> Self {
+        Command { session: Some((client, sequence)), data }
+    }
+
+    /// Create a command outside of any session.
+    pub fn without_session(data: Vec<u8>) -> Self {
+        Command { session: None, data }
+    }
+
+    /// Encode the command into the data of an entry.
+    ///
+    /// The layout is `0 | data` without a session, and
+    /// `1 | client (8) | sequence (8) | data` within one,
+    /// all integers in little endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(17 + self.data.len());
+        match self.session {
+            Some((client, sequence)) => {
+                data.push(1);
+                data.extend_from_slice(&client.to_le_bytes());
+                data.extend_from_slice(&sequence.to_le_bytes());
+            }
+            None => data.push(0),
+        }
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    /// Decode the command from the data of an entry.
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "Invalid command");
+        match data.first() {
+            Some(0) => Ok(Command::without_session(data[1..].to_vec())),
+            Some(1) if data.len() >= 17 => {
+                let client = u64::from_le_bytes(data[1..9].try_into().unwrap());
+                let sequence =
+                    u64::from_le_bytes(data[9..17].try_into().unwrap());
+                Ok(Command::new(client, sequence, data[17..].to_vec()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The last command applied for a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Session {
+    sequence: u64,
+    response: Response,
+}
+
+/// A state machine applying the commands of each client exactly once.
+///
+/// The data of every entry must be an encoded [`Command`]. The session
+/// table is part of the state, so it is included in the snapshots and
+/// identical on every node.
+pub struct Sessions<S> {
+    state_machine: S,
+    sessions: BTreeMap<ClientId, Session>,
+}
+
+impl<S: StateMachine> Sessions<S> {
+    /// Wrap the state machine, with no session yet.
+    pub fn new(state_machine: S) -> Self {
+        Sessions { state_machine, sessions: BTreeMap::new() }
+    }
+
+    /// Get the wrapped state machine.
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Get the last sequence number applied for the client, if any.
+    pub fn last_sequence(&self, client: ClientId) -> Option<u64> {
+        self.sessions.get(&client).map(|session| session.sequence)
+    }
+}
+
+impl<S: StateMachine> StateMachine for Sessions<S> {
+    /// Apply the command unless already applied for its client.
+    ///
+    /// A duplicate of the last command of the client gets the same
+    /// response. An older command was already completed for the client,
+    /// which no longer waits for it, so it gets an empty response.
+    /// Invalid data is not applied either.
+    fn apply(&mut self, entry: &Entry) -> Response {
+        let command = match Command::decode(&entry.data) {
+            Ok(command) => command,
+            Err(e) => {
+                log::error!("Skipped entry {}: {}", entry.index, e);
+                return Response::new();
+            }
+        };
+        let entry = Entry {
+            term: entry.term,
+            index: entry.index,
+            kind: entry.kind,
+            data: command.data,
+        };
+        let Some((client, sequence)) = command.session else {
+            return self.state_machine.apply(&entry);
+        };
+        match self.sessions.get(&client) {
+            Some(session) if session.sequence == sequence => {
+                log::debug!(
+                    "Skipped duplicate command {} of client {}.",
+                    sequence,
+                    client
+                );
+                return session.response.clone();
+            }
+            Some(session) if session.sequence > sequence => {
+                return Response::new();
+            }
+            _ => {}
+        }
+        let response = self.state_machine.apply(&entry);
+        let session = Session { sequence, response: response.clone() };
+        self.sessions.insert(client, session);
+        response
+    }
+
+    /// Save the session table, followed by the wrapped state machine.
+    ///
+    /// The layout is `sessions count (4)`, then for each session
+    /// `client (8) | sequence (8) | response length (4) | response`,
+    /// then the snapshot of the wrapped state machine, all integers in
+    /// little endian.
+    fn snapshot(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(self.sessions.len() as u32).to_le_bytes());
+        for (client, session) in &self.sessions {
+            data.extend_from_slice(&client.to_le_bytes());
+            data.extend_from_slice(&session.sequence.to_le_bytes());
+            let len = session.response.len() as u32;
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&session.response);
+        }
+        data.extend_from_slice(&self.state_machine.snapshot());
+        data
+    }
+
+    /// Restore the session table, then the wrapped state machine.
+    ///
+    /// Panics if the session table is invalid: going on with the
+    /// previous one would make the node diverge from the cluster.
+    fn restore(&mut self, snapshot: &[u8]) {
+        let Some((sessions, rest)) = decode_sessions(snapshot) else {
+            panic!("Invalid session table in snapshot");
+        };
+        self.sessions = sessions;
+        self.state_machine.restore(rest);
+    }
+}
+
+/// Decode the session table at the start of a snapshot,
+/// returning the rest of the snapshot with it.
+fn decode_sessions(
+    mut data: &[u8],
+) -> Option<(BTreeMap<ClientId, Session>, &[u8])> {
+    let mut take = |len: usize| {
+        let bytes = data.get(..len)?;
+        data = &data[len..];
+        Some(bytes)
+    };
+    let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+    let mut sessions = BTreeMap::new();
+    for _ in 0..count {
+        let client = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let sequence = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let response = take(len as usize)?.to_vec();
+        sessions.insert(client, Session { sequence, response });
+    }
+    Some((sessions, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state machine counting the applied entries.
+    #[derive(Default)]
+    struct Counter {
+        count: u64,
+    }
+
+    impl StateMachine for Counter {
+        fn apply(&mut self, _: &Entry) -> Response {
+            self.count += 1;
+            self.count.to_le_bytes().to_vec()
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            self.count.to_le_bytes().to_vec()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            self.count = u64::from_le_bytes(snapshot.try_into().unwrap());
+        }
+    }
+
+    fn entry(index: u64, command: Command) -> Entry {
+        Entry::new(1, index, command.encode())
+    }
+
+    #[test]
+    fn test_command_encode_decode() {
+        for command in [
+            Command::new(7, 3, b"x".to_vec()),
+            Command::without_session(b"y".to_vec()),
+        ] {
+            assert_eq!(Command::decode(&command.encode()).unwrap(), command);
+        }
+        assert!(Command::decode(&[]).is_err());
+        assert!(Command::decode(&[1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_not_applied() {
+        let mut sessions = Sessions::new(Counter::default());
+        let first = sessions.apply(&entry(1, Command::new(1, 1, vec![])));
+        let retry = sessions.apply(&entry(2, Command::new(1, 1, vec![])));
+        assert_eq!(first, retry);
+        assert_eq!(sessions.state_machine().count, 1);
+
+        // Other clients and commands are applied.
+        sessions.apply(&entry(3, Command::new(2, 1, vec![])));
+        sessions.apply(&entry(4, Command::new(1, 2, vec![])));
+        assert_eq!(sessions.state_machine().count, 3);
+        assert_eq!(sessions.last_sequence(1), Some(2));
+
+        // An older command is not applied either.
+        let stale = sessions.apply(&entry(5, Command::new(1, 1, vec![])));
+        assert!(stale.is_empty());
+        assert_eq!(sessions.state_machine().count, 3);
+
+        // Commands outside of a session are always applied.
+        sessions.apply(&entry(6, Command::without_session(vec![])));
+        sessions.apply(&entry(7, Command::without_session(vec![])));
+        assert_eq!(sessions.state_machine().count, 5);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut sessions = Sessions::new(Counter::default());
+        sessions.apply(&entry(1, Command::new(1, 1, vec![])));
+        sessions.apply(&entry(2, Command::new(2, 5, vec![])));
+
+        let mut restored = Sessions::new(Counter::default());
+        restored.restore(&sessions.snapshot());
+        assert_eq!(restored.state_machine().count, 2);
+        assert_eq!(restored.last_sequence(2), Some(5));
+
+        // A retry after the snapshot is still detected.
+        let retry = restored.apply(&entry(3, Command::new(1, 1, vec![])));
+        assert_eq!(retry, 1u64.to_le_bytes());
+        assert_eq!(restored.state_machine().count, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid session table")]
+    fn test_restore_invalid_sessions() {
+        let mut sessions = Sessions::new(Counter::default());
+        // One session announced, but none follows.
+        sessions.restore(&1u32.to_le_bytes());
+    }
+}
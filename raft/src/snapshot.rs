@@ -0,0 +1,100 @@
+//! Snapshots of the state machine, used to compact the log.
+
+use std::io;
+
+use tokio::time::Instant;
+
+use crate::membership::Membership;
+use crate::{Index, Term};
+
+/// A snapshot of the state machine, replacing all the log entries
+/// up to and including the last included index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Index of the last entry replaced by the snapshot.
+    pub last_included_index: Index,
+
+    /// Term of the last entry replaced by the snapshot.
+    pub last_included_term: Term,
+
+    /// Membership of the cluster as of the last entry replaced.
+    pub membership: Membership,
+
+    /// State of the state machine, as produced by
+    /// [`StateMachine::snapshot()`].
+    ///
+    /// [`StateMachine::snapshot()`]: crate::state_machine::StateMachine::snapshot
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Encode the snapshot into bytes.
+    ///
+    /// The layout is `last_included_index (8) | last_included_term (16) |
+    /// membership length (4) | membership | data`, all integers in little
+    /// endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let membership = self.membership.encode();
+        let mut data =
+            Vec::with_capacity(28 + membership.len() + self.data.len());
+        data.extend_from_slice(&self.last_included_index.to_le_bytes());
+        data.extend_from_slice(&self.last_included_term.to_le_bytes());
+        data.extend_from_slice(&(membership.len() as u32).to_le_bytes());
+        data.extend_from_slice(&membership);
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    /// Decode the snapshot from bytes produced by [`encode()`].
+    ///
+    /// [`encode()`]: #method.encode
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "Invalid snapshot");
+        if data.len() < 28 {
+            return Err(invalid());
+        }
+        let length =
+            u32::from_le_bytes(data[24..28].try_into().unwrap()) as usize;
+        let membership = data.get(28..28 + length).ok_or_else(invalid)?;
+        Ok(Snapshot {
+            last_included_index: Index::from_le_bytes(
+                data[..8].try_into().unwrap(),
+            ),
+            last_included_term: Term::from_le_bytes(
+                data[8..24].try_into().unwrap(),
+            ),
+            membership: Membership::decode(membership)?,
+            data: data[28 + length..].to_vec(),
+        })
+    }
+}
+
+/// A snapshot being sent to a peer, a single chunk at a time.
+pub(crate) struct SnapshotTransfer {
+    /// Last included index of the snapshot being sent.
+    pub(crate) last_included_index: Index,
+
+    /// Byte offset of the chunk in flight.
+    pub(crate) offset: u64,
+
+    /// When the chunk in flight was sent.
+    pub(crate) sent_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let snapshot = Snapshot {
+            last_included_index: 10,
+            last_included_term: 3,
+            membership: Membership::new(["127.0.0.1:1".parse().unwrap()]),
+            data: b"state".to_vec(),
+        };
+        assert_eq!(Snapshot::decode(&snapshot.encode()).unwrap(), snapshot);
+        assert!(Snapshot::decode(&[0; 27]).is_err());
+    }
+}
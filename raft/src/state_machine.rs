@@ -0,0 +1,61 @@
+//! The state machine that committed log entries are applied to.
+
+use crate::entry::Entry;
+
+/// Response of the state machine to an applied entry,
+/// returned to the client that proposed it.
+pub type Response = Vec<u8>;
+
+/// A user-supplied state machine replicated by Raft.
+///
+/// Raft only guarantees that every node applies the same entries in the
+/// same order. What an entry means is up to the implementation, as the
+/// data carried by [`Entry`] is opaque to Raft.
+///
+/// To keep the log from growing forever, the whole state is saved from
+/// time to time with [`snapshot()`], and the entries it covers are
+/// discarded. A node restarting or lagging too far behind the leader
+/// gets its state back with [`restore()`].
+///
+/// ``` rust
+/// use raft::entry::Entry;
+/// use raft::state_machine::StateMachine;
+///
+/// /// Sum of all the bytes ever applied.
+/// #[derive(Default)]
+/// struct Counter {
+///     sum: u64,
+/// }
+///
+/// impl StateMachine for Counter {
+///     fn apply(&mut self, entry: &Entry) -> Vec<u8> {
+///         self.sum += entry.data.iter().map(|&b| b as u64).sum::<u64>();
+///         self.sum.to_le_bytes().to_vec()
+///     }
+///
+///     fn snapshot(&self) -> Vec<u8> {
+///         self.sum.to_le_bytes().to_vec()
+///     }
+///
+///     fn restore(&mut self, snapshot: &[u8]) {
+///         self.sum = u64::from_le_bytes(snapshot.try_into().unwrap());
+///     }
+/// }
+/// ```
+///
+/// [`snapshot()`]: #tymethod.snapshot
+/// [`restore()`]: #tymethod.restore
+pub trait StateMachine {
+    /// Apply a committed entry, and return the response to the client.
+    ///
+    /// Entries are applied exactly once and in log order.
+    fn apply(&mut self, entry: &Entry) -> Response;
+
+    /// Save the whole state, covering all the entries applied so far.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replace the whole state with one saved by [`snapshot()`].
+    ///
+    /// [`snapshot()`]: #tymethod.snapshot
+    fn restore(&mut self, snapshot: &[u8]);
+}
@@ -0,0 +1,500 @@
+//! Stable storage of a Raft node.
+//!
+//! Raft requires part of the state of a node to survive crashes,
+//! otherwise a restarted node may vote twice in the same term,
+//! or forget entries it has acknowledged to the leader.
+//!
+//! - [`HardState`] holds the term, vote and commit index.
+//! - [`LogStorage`] holds the log entries, implemented by [`MemStorage`]
+//!   in memory and by [`WalStorage`] on disk.
+//! - [`SnapshotFile`] holds the latest [`Snapshot`], which replaces the
+//!   entries compacted from the log.
+
+pub mod wal;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use crate::entry::Entry;
+use crate::snapshot::Snapshot;
+use crate::{Index, Term};
+
+pub use wal::{SyncPolicy, WalStorage};
+
+/// Storage of the replicated log.
+///
+/// Entries are stored with consecutive indices from [`first_index()`]
+/// to [`last_index()`]. An empty log has a last index one less than
+/// its first index.
+///
+/// Writes must be durable when the methods return, as far as the
+/// implementation promises, since the node acknowledges entries to the
+/// leader right after appending them.
+///
+/// [`first_index()`]: #tymethod.first_index
+/// [`last_index()`]: #tymethod.last_index
+pub trait LogStorage {
+    /// Append entries at the end of the log.
+    ///
+    /// The first entry must directly follow the last entry in the log.
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()>;
+
+    /// Delete the entry at the given index and all that follow it.
+    fn truncate_suffix(&mut self, index: Index) -> io::Result<()>;
+
+    /// Get the entry at the given index, if it is in the log.
+    fn entry(&self, index: Index) -> Option<Entry>;
+
+    /// Get the term of the entry at the given index, if it is in the log.
+    fn term(&self, index: Index) -> Option<Term>;
+
+    /// Discard the entries up to and including the given index,
+    /// once they are covered by a snapshot.
+    ///
+    /// If the index is past the last entry, the log becomes empty
+    /// and the next entry appended must follow the given index.
+    fn compact(&mut self, index: Index) -> io::Result<()>;
+
+    /// Get the index of the first entry in the log.
+    fn first_index(&self) -> Index;
+
+    /// Get the index of the last entry in the log.
+    fn last_index(&self) -> Index;
+
+    /// Get the entries in the range `[low, high)`, skipping those already
+    /// compacted, and stopping at the end of the log.
+    fn entries(&self, low: Index, high: Index) -> Vec<Entry> {
+        (low.max(self.first_index())..high)
+            .map_while(|index| self.entry(index))
+            .collect()
+    }
+}
+
+/// A log storage that keeps all the entries in memory.
+///
+/// Nothing survives a restart, so it is meant for tests
+/// and for nodes that do not need durability.
+#[derive(Debug)]
+pub struct MemStorage {
+    /// Index of the first entry in the log.
+    first_index: Index,
+
+    /// Entries in the log.
+    entries: Vec<Entry>,
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemStorage {
+    /// Create an empty log storage.
+    pub fn new() -> Self {
+        MemStorage { first_index: 1, entries: Vec::new() }
+    }
+
+    /// Position of the entry with the given index in `entries`.
+    fn position(&self, index: Index) -> Option<usize> {
+        let position = index.checked_sub(self.first_index())? as usize;
+        (position < self.entries.len()).then_some(position)
+    }
+}
+
+impl LogStorage for MemStorage {
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        check_append(self.last_index(), entries)?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn truncate_suffix(&mut self, index: Index) -> io::Result<()> {
+        if let Some(position) = self.position(index) {
+            self.entries.truncate(position);
+        }
+        Ok(())
+    }
+
+    fn entry(&self, index: Index) -> Option<Entry> {
+        self.position(index).map(|p| self.entries[p].clone())
+    }
+
+    fn term(&self, index: Index) -> Option<Term> {
+        self.position(index).map(|p| self.entries[p].term)
+    }
+
+    fn compact(&mut self, index: Index) -> io::Result<()> {
+        if index < self.first_index {
+            return Ok(());
+        }
+        let compacted = (index - self.first_index + 1) as usize;
+        self.entries.drain(..compacted.min(self.entries.len()));
+        self.first_index = index + 1;
+        Ok(())
+    }
+
+    fn first_index(&self) -> Index {
+        self.first_index
+    }
+
+    fn last_index(&self) -> Index {
+        self.first_index() + self.entries.len() as Index - 1
+    }
+}
+
+/// Make sure the entries are consecutive and directly follow
+/// the last entry in the log.
+fn check_append(last_index: Index, entries: &[Entry]) -> io::Result<()> {
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.index != last_index + 1 + i as Index {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Entry {} does not follow the last index {}",
+                    entry.index,
+                    last_index + i as Index
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Persistent state of a node.
+///
+/// Updated on stable storage before responding to RPCs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HardState {
+    /// Latest term the node has seen.
+    pub term: Term,
+
+    /// Candidate that received vote in current term, if any.
+    pub voted_for: Option<SocketAddr>,
+
+    /// Index of highest log entry known to be committed.
+    pub commit: Index,
+}
+
+impl HardState {
+    /// Encode the hard state into bytes.
+    ///
+    /// The layout is `term (16) | commit (8) | voted_for`, all integers
+    /// in little endian. `voted_for` starts with a tag byte, `0` for none,
+    /// `4` for an IPv4 and `6` for an IPv6 socket address, followed by
+    /// the octets of the IP address and the port.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(43);
+        data.extend_from_slice(&self.term.to_le_bytes());
+        data.extend_from_slice(&self.commit.to_le_bytes());
+        match &self.voted_for {
+            None => data.push(0),
+            Some(addr) => encode_addr(addr, &mut data),
+        }
+        data
+    }
+
+    /// Decode the hard state from bytes produced by [`encode()`].
+    ///
+    /// [`encode()`]: #method.encode
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "Invalid hard state");
+        if data.len() < 25 {
+            return Err(invalid());
+        }
+        let term = Term::from_le_bytes(data[..16].try_into().unwrap());
+        let commit = Index::from_le_bytes(data[16..24].try_into().unwrap());
+        let voted_for = match &data[24..] {
+            [0] => None,
+            addr => match decode_addr(addr) {
+                Some((addr, size)) if size == data.len() - 24 => Some(addr),
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(HardState { term, voted_for, commit })
+    }
+}
+
+/// Encode a socket address at the end of the buffer.
+///
+/// The address starts with a tag byte, `4` for an IPv4 and `6` for an
+/// IPv6 socket address, followed by the octets of the IP address and
+/// the port in little endian.
+pub(crate) fn encode_addr(addr: &SocketAddr, buffer: &mut Vec<u8>) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&addr.ip().octets());
+            buffer.extend_from_slice(&addr.port().to_le_bytes());
+        }
+        SocketAddr::V6(addr) => {
+            buffer.push(6);
+            buffer.extend_from_slice(&addr.ip().octets());
+            buffer.extend_from_slice(&addr.port().to_le_bytes());
+        }
+    }
+}
+
+/// Decode the socket address at the start of the data, encoded by
+/// [`encode_addr()`].
+///
+/// Return the address and its encoded size, or `None` if invalid.
+pub(crate) fn decode_addr(data: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, size) = match data.first()? {
+        4 => {
+            let octets: [u8; 4] = data.get(1..5)?.try_into().unwrap();
+            (IpAddr::V4(Ipv4Addr::from(octets)), 5)
+        }
+        6 => {
+            let octets: [u8; 16] = data.get(1..17)?.try_into().unwrap();
+            (IpAddr::V6(Ipv6Addr::from(octets)), 17)
+        }
+        _ => return None,
+    };
+    let port =
+        u16::from_le_bytes(data.get(size..size + 2)?.try_into().unwrap());
+    Some((SocketAddr::new(ip, port), size + 2))
+}
+
+/// A file in a directory holding the [`HardState`] of a node.
+///
+/// Each save writes a temporary file and renames it over the old one,
+/// so a crash in the middle of a save leaves the previous state intact.
+pub struct HardStateFile {
+    /// Directory containing the file.
+    dir: PathBuf,
+
+    /// Path of the file.
+    path: PathBuf,
+}
+
+impl HardStateFile {
+    /// Name of the file in the directory.
+    const FILE_NAME: &'static str = "hard_state";
+
+    /// Open the hard state file in the given directory,
+    /// creating the directory if it does not exist.
+    ///
+    /// Return the file together with the state it holds,
+    /// or the default state on first boot.
+    pub fn open<P>(dir: P) -> io::Result<(Self, HardState)>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(Self::FILE_NAME);
+        let hard_state = match fs::read(&path) {
+            Ok(data) => HardState::decode(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                HardState::default()
+            }
+            Err(e) => return Err(e),
+        };
+        log::debug!("Loaded {:?} from {}", hard_state, path.display());
+        Ok((HardStateFile { dir, path }, hard_state))
+    }
+
+    /// Save the hard state, and wait until it reaches the disk.
+    pub fn save(&mut self, hard_state: &HardState) -> io::Result<()> {
+        write_atomically(&self.dir, &self.path, &hard_state.encode())
+    }
+}
+
+/// A file in a directory holding the latest [`Snapshot`] of a node.
+///
+/// Saved the same way as [`HardStateFile`].
+pub struct SnapshotFile {
+    /// Directory containing the file.
+    dir: PathBuf,
+
+    /// Path of the file.
+    path: PathBuf,
+}
+
+impl SnapshotFile {
+    /// Name of the file in the directory.
+    const FILE_NAME: &'static str = "snapshot";
+
+    /// Open the snapshot file in the given directory,
+    /// creating the directory if it does not exist.
+    ///
+    /// Return the file together with the snapshot it holds, if any.
+    pub fn open<P>(dir: P) -> io::Result<(Self, Option<Snapshot>)>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(Self::FILE_NAME);
+        let snapshot = match fs::read(&path) {
+            Ok(data) => Some(Snapshot::decode(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok((SnapshotFile { dir, path }, snapshot))
+    }
+
+    /// Save the snapshot, and wait until it reaches the disk.
+    pub fn save(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        write_atomically(&self.dir, &self.path, &snapshot.encode())
+    }
+}
+
+/// Replace the file at the given path in the directory with the data.
+///
+/// The data is written to a temporary file which is renamed over the old
+/// one, so a crash in the middle leaves either the old or the new data.
+fn write_atomically(dir: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // Make the rename itself durable.
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membership::Membership;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "raft_storage_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entries(
+        term: Term,
+        indices: std::ops::RangeInclusive<Index>,
+    ) -> Vec<Entry> {
+        indices.map(|i| Entry::new(term, i, vec![i as u8])).collect()
+    }
+
+    #[test]
+    fn test_mem_storage_empty() {
+        let storage = MemStorage::new();
+        assert_eq!(storage.first_index(), 1);
+        assert_eq!(storage.last_index(), 0);
+        assert_eq!(storage.entry(0), None);
+        assert_eq!(storage.term(1), None);
+    }
+
+    #[test]
+    fn test_mem_storage_append_and_truncate() {
+        let mut storage = MemStorage::new();
+        storage.append(&entries(1, 1..=3)).unwrap();
+        assert_eq!(storage.last_index(), 3);
+        assert_eq!(storage.term(2), Some(1));
+        assert_eq!(storage.entry(3), Some(Entry::new(1, 3, vec![3])));
+        assert_eq!(storage.entries(2, 10), entries(1, 2..=3));
+
+        storage.truncate_suffix(2).unwrap();
+        assert_eq!(storage.last_index(), 1);
+        storage.append(&entries(2, 2..=2)).unwrap();
+        assert_eq!(storage.term(2), Some(2));
+    }
+
+    #[test]
+    fn test_mem_storage_reject_gap() {
+        let mut storage = MemStorage::new();
+        assert!(storage.append(&entries(1, 2..=3)).is_err());
+        storage.append(&entries(1, 1..=1)).unwrap();
+        assert!(storage.append(&entries(1, 1..=1)).is_err());
+        assert_eq!(storage.last_index(), 1);
+    }
+
+    #[test]
+    fn test_mem_storage_compact() {
+        let mut storage = MemStorage::new();
+        storage.append(&entries(1, 1..=5)).unwrap();
+        storage.compact(3).unwrap();
+        assert_eq!(storage.first_index(), 4);
+        assert_eq!(storage.last_index(), 5);
+        assert_eq!(storage.entry(3), None);
+        assert_eq!(storage.entries(1, 10), entries(1, 4..=5));
+
+        // Compacting past the end leaves an empty log.
+        storage.compact(8).unwrap();
+        assert_eq!(storage.first_index(), 9);
+        assert_eq!(storage.last_index(), 8);
+        assert!(storage.append(&entries(2, 8..=8)).is_err());
+        storage.append(&entries(2, 9..=9)).unwrap();
+        assert_eq!(storage.term(9), Some(2));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let states = [
+            HardState::default(),
+            HardState {
+                term: u128::MAX,
+                voted_for: Some("172.19.0.2:16".parse().unwrap()),
+                commit: 42,
+            },
+            HardState {
+                term: 7,
+                voted_for: Some("[::1]:2024".parse().unwrap()),
+                commit: 0,
+            },
+        ];
+        for state in states {
+            assert_eq!(HardState::decode(&state.encode()).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(HardState::decode(&[0; 10]).is_err());
+
+        let mut data = HardState::default().encode();
+        data[24] = 4;
+        assert!(HardState::decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let dir = temp_dir("save_and_open");
+        let (mut file, state) = HardStateFile::open(&dir).unwrap();
+        assert_eq!(state, HardState::default());
+
+        let state = HardState {
+            term: 3,
+            voted_for: Some("127.0.0.1:1".parse().unwrap()),
+            commit: 2,
+        };
+        file.save(&state).unwrap();
+        drop(file);
+
+        let (_, reopened) = HardStateFile::open(&dir).unwrap();
+        assert_eq!(reopened, state);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_file() {
+        let dir = temp_dir("snapshot_file");
+        let (mut file, snapshot) = SnapshotFile::open(&dir).unwrap();
+        assert_eq!(snapshot, None);
+
+        let snapshot = Snapshot {
+            last_included_index: 5,
+            last_included_term: 2,
+            membership: Membership::new(["127.0.0.1:1".parse().unwrap()]),
+            data: vec![1, 2, 3],
+        };
+        file.save(&snapshot).unwrap();
+        let (_, reopened) = SnapshotFile::open(&dir).unwrap();
+        assert_eq!(reopened, Some(snapshot));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,683 @@
+//! A segmented write-ahead log on disk.
+//!
+//! The log is split into segment files in a directory, each named after
+//! the index of its first entry, e.g. `00000000000000000001.wal`.
+//! A new segment is started once the current one grows past the
+//! configured segment size, so that truncating the tail of the log
+//! never rewrites more than one file, and compacting the head of the
+//! log only deletes whole files.
+//!
+//! Each entry is written as one record:
+//!
+//! ``` txt
+//! | length (4) | crc32 (4) | term (16) | index (8) | kind (1) | data |
+//! ```
+//!
+//! where `length` is the size of the payload after the checksum, and
+//! `crc32` is the checksum of that payload, and `kind` is `0` for a
+//! normal entry, `1` for a membership change and `2` for a no-op.
+//! All integers are in little endian. A record that fails the checksum
+//! at the end of the last segment is the trace of a write torn by a
+//! crash, and is discarded when the log is opened.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::entry::{Entry, EntryKind};
+use crate::storage::{check_append, LogStorage};
+use crate::{Index, Term};
+
+/// Size of the record header, i.e. length and checksum.
+const HEADER_SIZE: usize = 8;
+
+/// Size of the fixed part of the payload, i.e. term, index and kind.
+const PAYLOAD_HEADER_SIZE: usize = 25;
+
+/// When appended entries are forced to reach the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every append. The only policy that keeps all the
+    /// guarantees of Raft when the whole machine crashes.
+    #[default]
+    Always,
+
+    /// Sync after every given number of appends.
+    /// Up to that many appends may be lost when the machine crashes.
+    Batch(usize),
+
+    /// Never sync, leave it to the operating system.
+    /// Entries survive a crash of the process, but not of the machine.
+    Never,
+}
+
+/// A segment file of the log.
+#[derive(Debug)]
+struct Segment {
+    /// Index of the first entry in the segment.
+    first_index: Index,
+
+    /// Path of the segment file.
+    path: PathBuf,
+}
+
+/// A log storage backed by a segmented write-ahead log on disk.
+///
+/// All the entries are also kept in memory, so reads never touch the
+/// disk, and the files are only read once when the log is opened.
+pub struct WalStorage {
+    /// Directory containing the segment files.
+    dir: PathBuf,
+
+    /// When appended entries are forced to reach the disk.
+    sync_policy: SyncPolicy,
+
+    /// Size in bytes after which a new segment is started.
+    segment_size: u64,
+
+    /// All the segments, ordered by their first index.
+    segments: Vec<Segment>,
+
+    /// Index of the first entry in the log. The first segment may still
+    /// hold some compacted entries before it.
+    first_index: Index,
+
+    /// The last segment, opened for appending.
+    active: File,
+
+    /// Current size of the last segment in bytes.
+    active_size: u64,
+
+    /// Entries in the log.
+    entries: Vec<Entry>,
+
+    /// Offset of each entry in its segment file.
+    offsets: Vec<u64>,
+
+    /// Number of appends since the last sync.
+    unsynced: usize,
+}
+
+impl WalStorage {
+    /// Default size in bytes after which a new segment is started.
+    pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Open the log in the given directory, creating the directory
+    /// if it does not exist.
+    ///
+    /// All the segments are read and checked, and a torn record at the
+    /// end of the log is discarded. A corrupted record anywhere else is
+    /// reported as [`io::ErrorKind::InvalidData`].
+    pub fn open<P>(dir: P, sync_policy: SyncPolicy) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().is_none_or(|ext| ext != "wal") {
+                continue;
+            }
+            let first_index = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<Index>().ok());
+            if let Some(first_index) = first_index {
+                segments.push(Segment { first_index, path });
+            }
+        }
+        segments.sort_by_key(|segment| segment.first_index);
+        if segments.is_empty() {
+            let path = segment_path(&dir, 1);
+            File::create(&path)?;
+            File::open(&dir)?.sync_all()?;
+            segments.push(Segment { first_index: 1, path });
+        }
+
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let last = segments.len() - 1;
+        for (i, segment) in segments.iter().enumerate() {
+            let expected = segments[0].first_index + entries.len() as Index;
+            if segment.first_index != expected {
+                return Err(invalid_data(format!(
+                    "Segment {} does not start at index {}",
+                    segment.path.display(),
+                    expected
+                )));
+            }
+
+            let data = fs::read(&segment.path)?;
+            let mut offset = 0;
+            while offset < data.len() {
+                let index = segments[0].first_index + entries.len() as Index;
+                match decode_record(&data[offset..], index) {
+                    Some((entry, size)) => {
+                        entries.push(entry);
+                        offsets.push(offset as u64);
+                        offset += size;
+                    }
+                    None if i == last => {
+                        log::warn!(
+                            "Discarded torn record at offset {} of {}",
+                            offset,
+                            segment.path.display()
+                        );
+                        let file = OpenOptions::new()
+                            .write(true)
+                            .open(&segment.path)?;
+                        file.set_len(offset as u64)?;
+                        file.sync_all()?;
+                        break;
+                    }
+                    None => {
+                        return Err(invalid_data(format!(
+                            "Corrupted record at offset {} of {}",
+                            offset,
+                            segment.path.display()
+                        )));
+                    }
+                }
+            }
+        }
+
+        let active_path = &segments[last].path;
+        let active = OpenOptions::new().append(true).open(active_path)?;
+        let active_size = active.metadata()?.len();
+        log::debug!(
+            "Opened write-ahead log {} with {} entries in {} segments",
+            dir.display(),
+            entries.len(),
+            segments.len()
+        );
+
+        let first_index = segments[0].first_index;
+        Ok(WalStorage {
+            dir,
+            sync_policy,
+            segment_size: Self::DEFAULT_SEGMENT_SIZE,
+            segments,
+            first_index,
+            active,
+            active_size,
+            entries,
+            offsets,
+            unsynced: 0,
+        })
+    }
+
+    /// Set the size in bytes after which a new segment is started.
+    pub fn set_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Get the policy of syncing appended entries.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Get the number of segment files.
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Force all the appended entries to reach the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.active.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Position of the entry with the given index in `entries`.
+    fn position(&self, index: Index) -> Option<usize> {
+        let position = index.checked_sub(self.first_index())? as usize;
+        (position < self.entries.len()).then_some(position)
+    }
+
+    /// Write the records of the entries, starting new segments as needed,
+    /// and record their offsets once written.
+    fn write_records(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let segment_first_index = self.segments.last().unwrap().first_index;
+            if self.active_size >= self.segment_size
+                && entry.index > segment_first_index
+            {
+                self.active.write_all(&buffer)?;
+                buffer.clear();
+                self.roll(entry.index)?;
+            }
+            offsets.push(self.active_size);
+            let size = encode_record(entry, &mut buffer);
+            self.active_size += size as u64;
+        }
+        self.active.write_all(&buffer)?;
+        self.offsets.extend(offsets);
+        Ok(())
+    }
+
+    /// Undo a failed append: remove the segments started since there were
+    /// the given number of them, and cut the last one back to its size.
+    fn rollback(
+        &mut self,
+        segments: usize,
+        active_size: u64,
+    ) -> io::Result<()> {
+        for segment in self.segments.drain(segments..) {
+            fs::remove_file(&segment.path)?;
+        }
+        let path = &self.segments.last().unwrap().path;
+        self.active = OpenOptions::new().append(true).open(path)?;
+        self.active.set_len(active_size)?;
+        self.active_size = active_size;
+        self.offsets.truncate(self.entries.len());
+        Ok(())
+    }
+
+    /// Start a new segment whose first entry has the given index.
+    fn roll(&mut self, first_index: Index) -> io::Result<()> {
+        self.active.sync_data()?;
+        let path = segment_path(&self.dir, first_index);
+        self.active =
+            OpenOptions::new().create(true).append(true).open(&path)?;
+        File::open(&self.dir)?.sync_all()?;
+        log::trace!("Started new segment {}", path.display());
+        self.segments.push(Segment { first_index, path });
+        self.active_size = 0;
+        Ok(())
+    }
+}
+
+impl LogStorage for WalStorage {
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        check_append(self.last_index(), entries)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // The entries are only recorded once written, so that a failed
+        // write leaves the log as it was.
+        let segments = self.segments.len();
+        let active_size = self.active_size;
+        if let Err(e) = self.write_records(entries) {
+            if let Err(e) = self.rollback(segments, active_size) {
+                log::error!("Failed to roll back write-ahead log: {}", e);
+            }
+            return Err(e);
+        }
+        self.entries.extend_from_slice(entries);
+
+        self.unsynced += 1;
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Batch(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn truncate_suffix(&mut self, index: Index) -> io::Result<()> {
+        let Some(position) = self.position(index.max(self.first_index()))
+        else {
+            return Ok(());
+        };
+        let index = self.entries[position].index;
+        let offset = self.offsets[position];
+
+        // Remove the segments starting after the truncated entry.
+        let keep = self.segments.partition_point(|s| s.first_index <= index);
+        for segment in self.segments.drain(keep..) {
+            fs::remove_file(&segment.path)?;
+        }
+
+        let path = &self.segments.last().unwrap().path;
+        self.active = OpenOptions::new().append(true).open(path)?;
+        self.active.set_len(offset)?;
+        self.active.sync_all()?;
+        File::open(&self.dir)?.sync_all()?;
+        self.active_size = offset;
+        self.unsynced = 0;
+
+        self.entries.truncate(position);
+        self.offsets.truncate(position);
+        log::debug!("Truncated write-ahead log from index {}", index);
+        Ok(())
+    }
+
+    fn entry(&self, index: Index) -> Option<Entry> {
+        self.position(index).map(|p| self.entries[p].clone())
+    }
+
+    fn term(&self, index: Index) -> Option<Term> {
+        self.position(index).map(|p| self.entries[p].term)
+    }
+
+    fn compact(&mut self, index: Index) -> io::Result<()> {
+        if index < self.first_index {
+            return Ok(());
+        }
+
+        if index >= self.last_index() {
+            // Nothing is left, start over with a single empty segment.
+            for segment in self.segments.drain(..) {
+                fs::remove_file(&segment.path)?;
+            }
+            let path = segment_path(&self.dir, index + 1);
+            self.active =
+                OpenOptions::new().create(true).append(true).open(&path)?;
+            self.segments.push(Segment { first_index: index + 1, path });
+            self.active_size = 0;
+            self.unsynced = 0;
+            self.entries.clear();
+            self.offsets.clear();
+        } else {
+            // Remove the segments whose entries are all compacted.
+            let remove =
+                self.segments.partition_point(|s| s.first_index <= index + 1)
+                    - 1;
+            for segment in self.segments.drain(..remove) {
+                fs::remove_file(&segment.path)?;
+            }
+            let compacted = (index + 1 - self.first_index) as usize;
+            self.entries.drain(..compacted);
+            self.offsets.drain(..compacted);
+        }
+        File::open(&self.dir)?.sync_all()?;
+        self.first_index = index + 1;
+        log::debug!("Compacted write-ahead log up to index {}", index);
+        Ok(())
+    }
+
+    fn first_index(&self) -> Index {
+        self.first_index
+    }
+
+    fn last_index(&self) -> Index {
+        self.first_index() + self.entries.len() as Index - 1
+    }
+}
+
+impl Drop for WalStorage {
+    /// Sync the entries left behind by [`SyncPolicy::Batch`].
+    fn drop(&mut self) {
+        if self.unsynced > 0 {
+            if let Err(e) = self.sync() {
+                log::error!("Failed to sync write-ahead log: {}", e);
+            }
+        }
+    }
+}
+
+/// Path of the segment file whose first entry has the given index.
+fn segment_path(dir: &Path, first_index: Index) -> PathBuf {
+    dir.join(format!("{:020}.wal", first_index))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Encode the entry as a record at the end of the buffer,
+/// and return the size of the record.
+fn encode_record(entry: &Entry, buffer: &mut Vec<u8>) -> usize {
+    let mut payload =
+        Vec::with_capacity(PAYLOAD_HEADER_SIZE + entry.data.len());
+    payload.extend_from_slice(&entry.term.to_le_bytes());
+    payload.extend_from_slice(&entry.index.to_le_bytes());
+    payload.push(entry.kind.tag());
+    payload.extend_from_slice(&entry.data);
+
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buffer.extend_from_slice(&payload);
+    HEADER_SIZE + payload.len()
+}
+
+/// Decode the record at the start of the data, expected to hold the
+/// entry with the given index.
+///
+/// Return the entry and the size of the record, or `None` if the record
+/// is incomplete, fails the checksum, or holds another index or an
+/// unknown kind.
+fn decode_record(data: &[u8], index: Index) -> Option<(Entry, usize)> {
+    let header = data.get(..HEADER_SIZE)?;
+    let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = data.get(HEADER_SIZE..HEADER_SIZE + length)?;
+    if length < PAYLOAD_HEADER_SIZE || crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let term = Term::from_le_bytes(payload[..16].try_into().unwrap());
+    let entry_index = Index::from_le_bytes(payload[16..24].try_into().unwrap());
+    if entry_index != index {
+        return None;
+    }
+    let kind = EntryKind::from_tag(payload[24])?;
+    let data = payload[PAYLOAD_HEADER_SIZE..].to_vec();
+    Some((Entry { term, index, kind, data }, HEADER_SIZE + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "raft_wal_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entries(
+        term: Term,
+        indices: std::ops::RangeInclusive<Index>,
+    ) -> Vec<Entry> {
+        indices.map(|i| Entry::new(term, i, vec![i as u8; 10])).collect()
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let entry = Entry::new(3, 7, b"hello".to_vec());
+        let mut buffer = Vec::new();
+        let size = encode_record(&entry, &mut buffer);
+        assert_eq!(size, buffer.len());
+        assert_eq!(decode_record(&buffer, 7), Some((entry, size)));
+        assert_eq!(decode_record(&buffer, 8), None);
+        assert_eq!(decode_record(&buffer[..size - 1], 7), None);
+
+        buffer[size - 1] ^= 0xff;
+        assert_eq!(decode_record(&buffer, 7), None);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = temp_dir("reopen");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.first_index(), 1);
+        assert_eq!(wal.last_index(), 0);
+        wal.append(&entries(1, 1..=3)).unwrap();
+        wal.append(&entries(2, 4..=5)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.last_index(), 5);
+        assert_eq!(wal.term(3), Some(1));
+        assert_eq!(wal.entry(5), entries(2, 5..=5).pop());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = temp_dir("segments");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Batch(4))
+            .unwrap()
+            .set_segment_size(100);
+        // Each record takes 42 bytes, so a segment holds 3 entries.
+        for index in 1..=10 {
+            wal.append(&entries(1, index..=index)).unwrap();
+        }
+        assert_eq!(wal.segment_count(), 4);
+        drop(wal);
+
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(wal.segment_count(), 4);
+        assert_eq!(wal.entries(1, 11), entries(1, 1..=10));
+
+        // Truncating in the middle of the second segment
+        // removes the last two segments.
+        wal.truncate_suffix(5).unwrap();
+        assert_eq!(wal.segment_count(), 2);
+        assert_eq!(wal.last_index(), 4);
+        wal.append(&entries(2, 5..=6)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(wal.last_index(), 6);
+        assert_eq!(wal.term(4), Some(1));
+        assert_eq!(wal.term(5), Some(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_failed_append() {
+        let dir = temp_dir("rollback");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always)
+            .unwrap()
+            .set_segment_size(100);
+        wal.append(&entries(1, 1..=2)).unwrap();
+
+        // Entries 3 - 6 are written across a new segment, then the
+        // append fails.
+        wal.write_records(&entries(1, 3..=6)).unwrap();
+        assert_eq!(wal.segment_count(), 2);
+        wal.rollback(1, 84).unwrap();
+        assert_eq!(wal.segment_count(), 1);
+        assert_eq!(wal.last_index(), 2);
+
+        // Truncating still cuts the segment at the right offset.
+        wal.truncate_suffix(2).unwrap();
+        wal.append(&entries(2, 2..=3)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.entries(1, 10)[..1], entries(1, 1..=1)[..]);
+        assert_eq!(wal.entries(2, 10), entries(2, 2..=3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_all() {
+        let dir = temp_dir("truncate_all");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        wal.append(&entries(1, 1..=3)).unwrap();
+        wal.truncate_suffix(0).unwrap();
+        assert_eq!(wal.last_index(), 0);
+        wal.append(&entries(2, 1..=1)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.entries(1, 10), entries(2, 1..=1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = temp_dir("compact");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always)
+            .unwrap()
+            .set_segment_size(100);
+        wal.append(&entries(1, 1..=10)).unwrap();
+        assert_eq!(wal.segment_count(), 4);
+
+        // Entries 1 - 3 fill the first segment, which is deleted.
+        wal.compact(4).unwrap();
+        assert_eq!(wal.segment_count(), 3);
+        assert_eq!(wal.first_index(), 5);
+        assert_eq!(wal.entry(4), None);
+        assert_eq!(wal.entries(1, 11), entries(1, 5..=10));
+
+        // Entries may be truncated right after the compacted ones.
+        wal.truncate_suffix(5).unwrap();
+        assert_eq!(wal.last_index(), 4);
+        wal.append(&entries(2, 5..=5)).unwrap();
+        drop(wal);
+
+        // The kept segment still starts with a compacted entry.
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.first_index(), 4);
+        assert_eq!(wal.last_index(), 5);
+        assert_eq!(wal.term(5), Some(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_all() {
+        let dir = temp_dir("compact_all");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        wal.append(&entries(1, 1..=3)).unwrap();
+        wal.compact(20).unwrap();
+        assert_eq!(wal.first_index(), 21);
+        assert_eq!(wal.last_index(), 20);
+        wal.append(&entries(2, 21..=22)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.segment_count(), 1);
+        assert_eq!(wal.entries(1, 30), entries(2, 21..=22));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_discard_torn_record() {
+        let dir = temp_dir("torn_record");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        wal.append(&entries(1, 1..=2)).unwrap();
+        drop(wal);
+
+        // Half of the third record was written before a crash.
+        let path = segment_path(&dir, 1);
+        let mut buffer = Vec::new();
+        encode_record(&entries(1, 3..=3)[0], &mut buffer);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&buffer[..buffer.len() / 2]).unwrap();
+        drop(file);
+
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.last_index(), 2);
+        wal.append(&entries(1, 3..=3)).unwrap();
+        drop(wal);
+
+        let wal = WalStorage::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(wal.entries(1, 10), entries(1, 1..=3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_corrupted_segment() {
+        let dir = temp_dir("corrupted_segment");
+        let mut wal = WalStorage::open(&dir, SyncPolicy::Always)
+            .unwrap()
+            .set_segment_size(100);
+        wal.append(&entries(1, 1..=6)).unwrap();
+        assert_eq!(wal.segment_count(), 2);
+        drop(wal);
+
+        // Flip a byte of the first record in the first segment.
+        let path = segment_path(&dir, 1);
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_SIZE] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let error = WalStorage::open(&dir, SyncPolicy::Always).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
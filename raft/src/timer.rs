@@ -0,0 +1,211 @@
+//! Election and heartbeat timers driven by [`tokio`].
+
+use std::ops::RangeInclusive;
+
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+/// A randomized election timer.
+///
+/// The timer does not block. Instead, [`elapsed()`] returns a future
+/// that completes at the deadline, so it can be raced with incoming
+/// messages in [`tokio::select!`] and simply dropped when a message
+/// wins. Each [`reset()`] picks a new random timeout, so that nodes
+/// rarely time out together and split the votes.
+///
+/// [`elapsed()`]: #method.elapsed
+/// [`reset()`]: #method.reset
+#[derive(Debug)]
+pub struct ElectionTimer {
+    /// Range of the random timeout in milliseconds.
+    range: RangeInclusive<u16>,
+
+    /// The current timeout.
+    timeout: Duration,
+
+    /// When the timer fires, `None` if cancelled.
+    deadline: Option<Instant>,
+}
+
+impl ElectionTimer {
+    /// Default range of the election timeout in milliseconds.
+    pub const DEFAULT_RANGE: RangeInclusive<u16> = 150..=300;
+
+    /// Create a started timer with a random timeout in the given range
+    /// of milliseconds.
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        let mut timer =
+            ElectionTimer { range, timeout: Duration::ZERO, deadline: None };
+        timer.reset();
+        timer
+    }
+
+    /// Restart the timer with a new random timeout.
+    pub fn reset(&mut self) {
+        let millis = rand::thread_rng().gen_range(self.range.clone());
+        self.timeout = Duration::from_millis(millis as u64);
+        self.deadline = Some(Instant::now() + self.timeout);
+    }
+
+    /// Stop the timer, it never fires until the next reset.
+    pub fn cancel(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Check if the timer has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.deadline.is_none()
+    }
+
+    /// Get the current timeout.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Get when the timer fires, `None` if cancelled.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Wait until the timer fires.
+    ///
+    /// The future only captures the current deadline, so a reset or a
+    /// cancel takes effect on the next call. If the timer is cancelled,
+    /// the future never completes.
+    pub fn elapsed(&self) -> impl std::future::Future<Output = ()> + 'static {
+        let deadline = self.deadline;
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+impl Default for ElectionTimer {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_RANGE)
+    }
+}
+
+/// A periodic heartbeat timer of the leader.
+///
+/// Like [`ElectionTimer`], it never blocks, and only runs while started.
+#[derive(Debug)]
+pub struct HeartbeatTimer {
+    /// Interval between heartbeats.
+    interval: Duration,
+
+    /// When the next heartbeat is due, `None` if stopped.
+    deadline: Option<Instant>,
+}
+
+impl HeartbeatTimer {
+    /// Create a stopped timer with the given interval in milliseconds.
+    pub fn new(interval: u16) -> Self {
+        HeartbeatTimer {
+            interval: Duration::from_millis(interval as u64),
+            deadline: None,
+        }
+    }
+
+    /// Schedule the next heartbeat one interval from now.
+    pub fn start(&mut self) {
+        self.deadline = Some(Instant::now() + self.interval);
+    }
+
+    /// Stop the timer, no heartbeat is due until the next start.
+    pub fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Check if the timer is running.
+    pub fn is_running(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Get the interval between heartbeats.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Wait until the next heartbeat is due.
+    ///
+    /// If the timer is stopped, the future never completes.
+    pub fn elapsed(&self) -> impl std::future::Future<Output = ()> + 'static {
+        let deadline = self.deadline;
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_elapsed() {
+        let start = Instant::now();
+        let timer = ElectionTimer::default();
+        timer.elapsed().await;
+        let elapsed = start.elapsed();
+        assert_eq!(elapsed, timer.timeout());
+        assert!(elapsed >= Duration::from_millis(150));
+        assert!(elapsed <= Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset() {
+        let mut timer = ElectionTimer::new(200..=200);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        timer.reset();
+
+        let start = Instant::now();
+        timer.elapsed().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel() {
+        let mut timer = ElectionTimer::default();
+        timer.cancel();
+        assert!(timer.is_cancelled());
+        let result =
+            tokio::time::timeout(Duration::from_secs(10), timer.elapsed())
+                .await;
+        assert!(result.is_err());
+
+        timer.reset();
+        assert!(!timer.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_timer() {
+        let mut timer = HeartbeatTimer::new(50);
+        assert!(!timer.is_running());
+        let timeout = Duration::from_secs(10);
+        assert!(tokio::time::timeout(timeout, timer.elapsed()).await.is_err());
+
+        timer.start();
+        let start = Instant::now();
+        timer.elapsed().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+
+        timer.stop();
+        assert!(!timer.is_running());
+    }
+
+    #[test]
+    fn test_random_range() {
+        for _ in 0..100 {
+            let timer = ElectionTimer::new(150..=300);
+            let millis = timer.timeout().as_millis();
+            assert!((150..=300).contains(&millis));
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! Client side of the services, keeping a connection to each peer.
+//!
+//! A peer that cannot be reached is not retried on every request: after
+//! each failure to connect, the next attempt waits for a delay doubling
+//! with every failure, up to a maximum. The delay is randomized, so that
+//! the nodes do not all reconnect at once to a peer coming back.
+//!
+//! Each phase of a request has a timeout, see [`Timeouts`], so that a
+//! peer which is down or too slow fails the request with
+//! [`CallError::Timeout`] instead of holding it forever.
+
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+use crate::connection::Connection;
+use crate::error::{CallError, Phase};
+use crate::{frame, RpcRequest, RpcResponse};
+
+/// Default delay before reconnecting after a first failure.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Default maximum delay before reconnecting.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Default timeout to connect to a service.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default timeout to write a request.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default timeout to wait for a response.
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeouts of the phases of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Timeout to connect to the service, if not connected yet.
+    pub connect: Duration,
+
+    /// Timeout to write the request, including the time waiting for the
+    /// requests before it on the connection.
+    pub write: Duration,
+
+    /// Timeout to wait for the response, once the request is written.
+    pub response: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            write: DEFAULT_WRITE_TIMEOUT,
+            response: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+/// Health of the connection to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// No connection is open, the next request opens one.
+    Disconnected,
+
+    /// A connection is open.
+    Connected,
+
+    /// The last `failures` attempts to connect failed,
+    /// and the next one is not made before `retry_at`.
+    Backoff { failures: u32, retry_at: Instant },
+}
+
+/// State of a peer in the pool.
+#[derive(Default)]
+struct Peer {
+    /// Connection to the peer, if any.
+    connection: Option<Arc<Connection>>,
+
+    /// Number of failures to connect since the last success.
+    failures: u32,
+
+    /// Time of the next attempt to connect, after a failure.
+    retry_at: Option<Instant>,
+
+    /// Held while connecting to the peer, so that concurrent requests
+    /// wait for a single attempt instead of each opening a connection.
+    connecting: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Client sending requests to services.
+///
+/// A single connection to each peer is shared by all the requests to it,
+/// and by the clones of the client. A closed connection is reopened by
+/// the next request, unless the peer is backing off: the request then
+/// fails right away with [`io::ErrorKind::NotConnected`].
+pub struct Client<Req, Res> {
+    /// Peers the client sent requests to.
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+
+    /// Delay before reconnecting after a first failure.
+    initial_backoff: Duration,
+
+    /// Maximum delay before reconnecting.
+    max_backoff: Duration,
+
+    /// Maximum size in bytes of a request or response.
+    max_frame_size: u32,
+
+    /// Timeouts of the requests, unless given for a request.
+    timeouts: Timeouts,
+
+    /// Request and response types.
+    types: PhantomData<fn(Req) -> Res>,
+}
+
+impl<Req, Res> Clone for Client<Req, Res> {
+    fn clone(&self) -> Self {
+        Client {
+            peers: self.peers.clone(),
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+            max_frame_size: self.max_frame_size,
+            timeouts: self.timeouts,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<Req, Res> Default for Client<Req, Res>
+where
+    Req: RpcRequest,
+    Res: RpcResponse,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Req, Res> Client<Req, Res>
+where
+    Req: RpcRequest,
+    Res: RpcResponse,
+{
+    /// Create a new client, with no connection yet.
+    pub fn new() -> Self {
+        Client {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            timeouts: Timeouts::default(),
+            types: PhantomData,
+        }
+    }
+
+    /// Set the delay before reconnecting after a first failure.
+    pub fn set_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Get the delay before reconnecting after a first failure.
+    pub fn initial_backoff(&self) -> Duration {
+        self.initial_backoff
+    }
+
+    /// Set the maximum delay before reconnecting.
+    pub fn set_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Get the maximum delay before reconnecting.
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// Set the maximum size in bytes of a request or response.
+    pub fn set_max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Get the maximum size in bytes of a request or response.
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    /// Set the timeouts of the requests, unless given for a request.
+    pub fn set_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Get the timeouts of the requests, unless given for a request.
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Get the health of the connection to the peer.
+    pub fn health(&self, target: SocketAddr) -> Health {
+        let peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get(&target) else {
+            return Health::Disconnected;
+        };
+        match (&peer.connection, peer.retry_at) {
+            (Some(connection), _) if !connection.is_closed() => {
+                Health::Connected
+            }
+            (_, Some(retry_at)) if Instant::now() < retry_at => {
+                Health::Backoff { failures: peer.failures, retry_at }
+            }
+            _ => Health::Disconnected,
+        }
+    }
+
+    /// Close the connection to the peer, and forget its failures.
+    pub fn remove(&self, target: SocketAddr) {
+        self.peers.lock().unwrap().remove(&target);
+    }
+
+    /// Send a request to the target service, and wait for the response,
+    /// with the timeouts of the client.
+    pub async fn call(
+        &self,
+        target: SocketAddr,
+        request: &Req,
+    ) -> Result<Res, CallError> {
+        self.call_with_timeouts(target, request, self.timeouts).await
+    }
+
+    /// Send a request to the target service, and wait for the response,
+    /// with the given timeouts.
+    ///
+    /// Dropping the returned future cancels the request: its response is
+    /// ignored if it comes.
+    /// As sending a request is the main purpose,
+    /// log output of sending is at [`log::info!`] level,
+    /// whereas receiving is at [`log::debug!`] level.
+    pub async fn call_with_timeouts(
+        &self,
+        target: SocketAddr,
+        request: &Req,
+        timeouts: Timeouts,
+    ) -> Result<Res, CallError> {
+        let connection = self.connect(target, timeouts.connect).await?;
+
+        let data = request.serialize();
+        let summary = request.to_string();
+        let response = connection.call(&data, &summary, &timeouts).await?;
+        let response = Res::deserialize(response);
+        log::debug!(
+            "Received response [{}] from {}",
+            response.to_string(),
+            target
+        );
+
+        Ok(response)
+    }
+
+    /// Get the open connection to the target, or open one,
+    /// unless backing off from it.
+    ///
+    /// A single attempt to connect to the target is made at a time, the
+    /// other requests wait for it and share its connection.
+    async fn connect(
+        &self,
+        target: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Arc<Connection>, CallError> {
+        if let Some(connection) = self.open_connection(target)? {
+            return Ok(connection);
+        }
+        let connecting = {
+            let mut peers = self.peers.lock().unwrap();
+            peers.entry(target).or_default().connecting.clone()
+        };
+        let deadline = Instant::now() + timeout;
+        let timed_out = CallError::Timeout { phase: Phase::Connect, timeout };
+        let Ok(_connecting) =
+            tokio::time::timeout_at(deadline, connecting.lock()).await
+        else {
+            return Err(timed_out);
+        };
+        // Another request may have connected, or failed to, meanwhile.
+        if let Some(connection) = self.open_connection(target)? {
+            return Ok(connection);
+        }
+
+        let connection = Connection::connect(target, self.max_frame_size);
+        let connection =
+            match tokio::time::timeout_at(deadline, connection).await {
+                Ok(connection) => connection.map_err(CallError::Io),
+                Err(_) => Err(timed_out),
+            };
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(target).or_default();
+        match connection {
+            Ok(connection) => {
+                let connection = Arc::new(connection);
+                peer.connection = Some(connection.clone());
+                peer.failures = 0;
+                peer.retry_at = None;
+                Ok(connection)
+            }
+            Err(e) => {
+                peer.failures += 1;
+                let delay = self.backoff(peer.failures);
+                peer.retry_at = Some(Instant::now() + delay);
+                log::error!(
+                    "Failed to connect to {}: {}, retrying in {:?}",
+                    target,
+                    e,
+                    delay
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the open connection to the target if any,
+    /// or fail if backing off from it.
+    fn open_connection(
+        &self,
+        target: SocketAddr,
+    ) -> Result<Option<Arc<Connection>>, CallError> {
+        let peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get(&target) else {
+            return Ok(None);
+        };
+        match (&peer.connection, peer.retry_at) {
+            (Some(connection), _) if !connection.is_closed() => {
+                Ok(Some(connection.clone()))
+            }
+            (_, Some(retry_at)) if Instant::now() < retry_at => {
+                Err(CallError::Io(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!(
+                        "Not reconnecting to {} after {} failures",
+                        target, peer.failures
+                    ),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the delay before reconnecting after the given number of
+    /// failures in a row, picked at random between half the full delay
+    /// and the full delay.
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PingRequest, PingResponse, PingService};
+
+    type PingClient = Client<PingRequest, PingResponse>;
+
+    /// Get an address of the loopback interface that is not in use.
+    async fn free_addr() -> SocketAddr {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let client = PingClient::new()
+            .set_initial_backoff(Duration::from_millis(100))
+            .set_max_backoff(Duration::from_secs(1));
+        for (failures, full) in [(1, 100), (2, 200), (3, 400), (4, 800)] {
+            let delay = client.backoff(failures);
+            let full = Duration::from_millis(full);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+        for failures in [5, 10, 100, u32::MAX] {
+            let delay = client.backoff(failures);
+            assert!(delay >= Duration::from_millis(500), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(1), "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_backoff() {
+        let client =
+            PingClient::new().set_initial_backoff(Duration::from_millis(50));
+        let target = free_addr().await;
+        let request = PingRequest::new("Ping".into());
+        assert_eq!(client.health(target), Health::Disconnected);
+
+        let e = client.call(target, &request).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        let Health::Backoff { failures: 1, retry_at } = client.health(target)
+        else {
+            panic!("Unexpected health {:?}", client.health(target));
+        };
+
+        // No attempt is made while backing off.
+        let service = PingService::new(target, request.clone());
+        let echo = |request: PingRequest, _| async move {
+            PingResponse::new(request.to_string())
+        };
+        let task =
+            tokio::spawn(async move { service.handle_request(echo).await });
+        let e = client.call(target, &request).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+
+        // Then the peer can be reconnected to.
+        tokio::time::sleep_until(retry_at).await;
+        assert_eq!(client.health(target), Health::Disconnected);
+        let response = client.call(target, &request).await.unwrap();
+        assert_eq!(response.to_string(), "Ping");
+        assert_eq!(client.health(target), Health::Connected);
+
+        // Clones share the connection.
+        assert_eq!(client.clone().health(target), Health::Connected);
+        client.remove(target);
+        assert_eq!(client.health(target), Health::Disconnected);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_single_connect_attempt() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            let wait = Duration::from_millis(200);
+            while let Ok(Ok((stream, _))) =
+                tokio::time::timeout(wait, listener.accept()).await
+            {
+                streams.push(stream);
+            }
+            streams.len()
+        });
+
+        // The first requests to the peer share a single connection.
+        let timeouts = Timeouts {
+            response: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let client = PingClient::new().set_timeouts(timeouts);
+        let request = PingRequest::new("Ping".into());
+        let (first, second, third) = tokio::join!(
+            client.call(target, &request),
+            client.call(target, &request),
+            client.call(target, &request),
+        );
+        for result in [first, second, third] {
+            assert!(result.unwrap_err().is_timeout());
+        }
+        assert_eq!(accepted.await.unwrap(), 1);
+    }
+}
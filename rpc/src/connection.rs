@@ -0,0 +1,358 @@
+//! Long-lived connections, with several requests in flight at once.
+//!
+//! Each message in a frame starts with the id of its request on 8 bytes in
+//! little endian, and the response carries the id of its request, so the
+//! responses can be sent in any order, as soon as each one is ready.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::client::Timeouts;
+use crate::error::{CallError, Phase};
+use crate::{frame, Bytes};
+
+/// Identifier of a request on a connection.
+pub type RequestId = u64;
+
+/// Size of the request id at the start of a message in bytes.
+pub const ID_SIZE: usize = 8;
+
+/// Tag the message with the id of its request.
+pub(crate) fn encode_message(id: RequestId, message: &[u8]) -> Bytes {
+    let mut data = Vec::with_capacity(ID_SIZE + message.len());
+    data.extend_from_slice(&id.to_le_bytes());
+    data.extend_from_slice(message);
+    data
+}
+
+/// Split a tagged message into the id of its request and the message.
+pub(crate) fn decode_message(
+    mut data: Bytes,
+) -> io::Result<(RequestId, Bytes)> {
+    if data.len() < ID_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message too short for a request id",
+        ));
+    }
+    let id = RequestId::from_le_bytes(data[..ID_SIZE].try_into().unwrap());
+    data.drain(..ID_SIZE);
+    Ok((id, data))
+}
+
+/// Calls waiting for their response, `None` once the connection is closed.
+type Pending = Arc<Mutex<Option<HashMap<RequestId, Waiter>>>>;
+
+/// Where to send the response of a call.
+type Waiter = oneshot::Sender<io::Result<Bytes>>;
+
+/// A frame to write, with where to report the outcome.
+type Outgoing = (Bytes, oneshot::Sender<io::Result<()>>);
+
+/// A connection to a service, shared by concurrent calls.
+///
+/// The frames are written by a task of the connection, so that a call
+/// given up half way does not leave a partial frame on the stream.
+/// Once the stream fails, the connection is closed: the calls in flight
+/// fail, and so do the next ones.
+pub struct Connection {
+    /// Address of the service.
+    target: SocketAddr,
+
+    /// Calls waiting for their response.
+    pending: Pending,
+
+    /// Id of the next request.
+    next_id: AtomicU64,
+
+    /// Frames to write.
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+
+    /// Task reading the responses.
+    reader: JoinHandle<()>,
+
+    /// Task writing the requests.
+    writer: JoinHandle<()>,
+}
+
+impl Connection {
+    /// Connect to the service.
+    pub async fn connect(
+        target: SocketAddr,
+        max_frame_size: u32,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(target).await?;
+        stream.set_nodelay(true)?;
+        log::trace!("Connected to {:?}", target);
+
+        let (read_half, write_half) = stream.into_split();
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (outgoing, frames) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_responses(
+            read_half,
+            pending.clone(),
+            max_frame_size,
+        ));
+        let writer = tokio::spawn(write_requests(
+            write_half,
+            frames,
+            pending.clone(),
+            max_frame_size,
+        ));
+        Ok(Connection {
+            target,
+            pending,
+            next_id: AtomicU64::new(0),
+            outgoing,
+            reader,
+            writer,
+        })
+    }
+
+    /// Get the address of the service.
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Check whether the connection is closed, after a failure.
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    /// Send the request, and wait for its response,
+    /// within the write and response timeouts.
+    /// The request is logged as `summary` once written.
+    ///
+    /// Other calls may be in flight on the connection at the same time.
+    /// If the call is given up, or times out waiting for the response,
+    /// its response is ignored when received. If it times out writing
+    /// the request, the peer is not reading, so the connection is closed.
+    pub async fn call(
+        &self,
+        request: &[u8],
+        summary: &str,
+        timeouts: &Timeouts,
+    ) -> Result<Bytes, CallError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (waiter, response) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, waiter),
+            None => return Err(closed().into()),
+        };
+        let _guard = PendingGuard { pending: &self.pending, id };
+
+        let (written, write_result) = oneshot::channel();
+        let frame = encode_message(id, request);
+        self.outgoing.send((frame, written)).map_err(|_| closed())?;
+        match tokio::time::timeout(timeouts.write, write_result).await {
+            Ok(result) => {
+                result.map_err(|_| closed())??;
+                log::info!("Sent request [{}] to {}", summary, self.target);
+            }
+            Err(_) => {
+                let timeout = timeouts.write;
+                let e = CallError::Timeout { phase: Phase::Write, timeout };
+                self.close(&e.to_string());
+                return Err(e);
+            }
+        }
+
+        match tokio::time::timeout(timeouts.response, response).await {
+            Ok(result) => Ok(result.map_err(|_| closed())??),
+            Err(_) => {
+                let timeout = timeouts.response;
+                Err(CallError::Timeout { phase: Phase::Response, timeout })
+            }
+        }
+    }
+
+    /// Close the connection, failing the calls in flight.
+    fn close(&self, reason: &str) {
+        log::debug!("Closing connection to {}: {}", self.target, reason);
+        let e = io::Error::new(io::ErrorKind::ConnectionAborted, reason);
+        close(&self.pending, &e);
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+/// Remove a call from the pending ones, once completed or given up.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: RequestId,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// Error of a call on a closed connection.
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed")
+}
+
+/// Close the connection, failing the calls in flight with the error.
+fn close(pending: &Pending, error: &io::Error) {
+    let Some(waiters) = pending.lock().unwrap().take() else {
+        return;
+    };
+    for (_, waiter) in waiters {
+        let _ =
+            waiter.send(Err(io::Error::new(error.kind(), error.to_string())));
+    }
+}
+
+/// Hand each response to the call waiting for it, until the stream fails.
+async fn read_responses(
+    mut reader: OwnedReadHalf,
+    pending: Pending,
+    max_frame_size: u32,
+) {
+    loop {
+        let message = frame::read_frame(&mut reader, max_frame_size).await;
+        let (id, response) = match message.and_then(decode_message) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Connection closed: {}", e);
+                close(&pending, &e);
+                return;
+            }
+        };
+        let waiter = match pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.remove(&id),
+            None => return,
+        };
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(Ok(response));
+            }
+            None => log::trace!("Ignored response to request {}", id),
+        }
+    }
+}
+
+/// Write the frames in order, until the stream fails.
+async fn write_requests(
+    mut writer: OwnedWriteHalf,
+    mut frames: mpsc::UnboundedReceiver<Outgoing>,
+    pending: Pending,
+    max_frame_size: u32,
+) {
+    while let Some((frame, written)) = frames.recv().await {
+        match frame::write_frame(&mut writer, &frame, max_frame_size).await {
+            // A frame too large is not written, the stream is still fine.
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                let _ = written.send(Err(e));
+            }
+            Err(e) => {
+                close(&pending, &e);
+                let _ = written.send(Err(e));
+                return;
+            }
+            Ok(()) => {
+                let _ = written.send(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_encode_decode_message() {
+        let data = encode_message(7, b"ping");
+        assert_eq!(data.len(), ID_SIZE + 4);
+        assert_eq!(decode_message(data).unwrap(), (7, b"ping".to_vec()));
+
+        let e = decode_message(vec![0; ID_SIZE - 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let connection = Connection::connect(target, 1024).await.unwrap();
+        assert_eq!(connection.target(), target);
+
+        // The service reads the request, then closes the connection.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(async move {
+            frame::read_frame(&mut stream, 1024).await.unwrap()
+        });
+        let e = connection
+            .call(b"ping", "ping", &Timeouts::default())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let request = decode_message(server.await.unwrap()).unwrap();
+        assert_eq!(request, (0, b"ping".to_vec()));
+
+        assert!(connection.is_closed());
+        let e = connection
+            .call(b"ping", "ping", &Timeouts::default())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let connection = Connection::connect(target, 16).await.unwrap();
+
+        let e = connection
+            .call(&[0; 16], "large", &Timeouts::default())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(!connection.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let connection = Connection::connect(target, 1 << 30).await.unwrap();
+
+        // The service never reads, so the request fills the buffers.
+        let timeouts = Timeouts {
+            write: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let request = vec![0; 64 << 20];
+        let e =
+            connection.call(&request, "large", &timeouts).await.unwrap_err();
+        assert!(matches!(e, CallError::Timeout { phase: Phase::Write, .. }));
+
+        // The stream may hold part of the request, so it is closed.
+        assert!(connection.is_closed());
+        drop(listener);
+    }
+}
@@ -0,0 +1,95 @@
+//! Errors of the requests sent to a service.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use tokio::time::Duration;
+
+/// Phase of a request which did not complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Connecting to the service.
+    Connect,
+
+    /// Writing the request.
+    Write,
+
+    /// Waiting for the response, once the request is written.
+    Response,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Connect => write!(f, "connect"),
+            Phase::Write => write!(f, "write"),
+            Phase::Response => write!(f, "response"),
+        }
+    }
+}
+
+/// Reasons why a request did not get a response.
+#[derive(Debug)]
+pub enum CallError {
+    /// The phase did not complete within its timeout, such as with a
+    /// peer that is down or too slow to be waited for.
+    Timeout { phase: Phase, timeout: Duration },
+
+    /// The request failed, such as when the connection is refused or
+    /// closed, or the message is too large.
+    Io(io::Error),
+}
+
+impl CallError {
+    /// Check whether the request timed out.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, CallError::Timeout { .. })
+    }
+
+    /// Get the kind of the error as an I/O error,
+    /// [`io::ErrorKind::TimedOut`] for a timeout.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            CallError::Timeout { .. } => io::ErrorKind::TimedOut,
+            CallError::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Timeout { phase, timeout } => {
+                write!(f, "Timed out after {:?} on {}", timeout, phase)
+            }
+            CallError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CallError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CallError::Timeout { .. } => None,
+            CallError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for CallError {
+    fn from(e: io::Error) -> Self {
+        CallError::Io(e)
+    }
+}
+
+impl From<CallError> for io::Error {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::Timeout { .. } => {
+                io::Error::new(io::ErrorKind::TimedOut, e.to_string())
+            }
+            CallError::Io(e) => e,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Length-prefixed framing of the messages on a stream.
+//!
+//! Each message is sent as a frame: its length on 4 bytes in little
+//! endian, followed by the message itself. A stream only delivers bytes,
+//! possibly split or merged in any way, so the length tells the receiver
+//! where each message ends, whatever its content.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Bytes;
+
+/// Size of the length prefix of a frame in bytes.
+pub const HEADER_SIZE: usize = 4;
+
+/// Default maximum size of a message in a frame, 16 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Write the message as a single frame.
+///
+/// Fail with [`io::ErrorKind::InvalidInput`] if the message is larger
+/// than `max_frame_size`, without writing anything.
+pub async fn write_frame<W>(
+    writer: &mut W,
+    message: &[u8],
+    max_frame_size: u32,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if message.len() > max_frame_size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                message.len(),
+                max_frame_size
+            ),
+        ));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Read the message of the next frame.
+///
+/// Fail with [`io::ErrorKind::InvalidData`] if the frame announces more
+/// than `max_frame_size` bytes, before reading them, and with
+/// [`io::ErrorKind::UnexpectedEof`] if the stream ends within a frame,
+/// or before any frame.
+pub async fn read_frame<R>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> io::Result<Bytes>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let len = u32::from_le_bytes(header);
+    if len > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                len, max_frame_size
+            ),
+        ));
+    }
+    let mut message = vec![0; len as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_read_frames() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let large = vec![7; 10_000];
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, b"ping", 1 << 20).await.unwrap();
+            write_frame(&mut client, &[], 1 << 20).await.unwrap();
+            write_frame(&mut client, &large, 1 << 20).await.unwrap();
+        });
+        // The duplex buffer is smaller than the large frame,
+        // so it is read in several parts.
+        assert_eq!(read_frame(&mut server, 1 << 20).await.unwrap(), b"ping");
+        assert!(read_frame(&mut server, 1 << 20).await.unwrap().is_empty());
+        assert_eq!(
+            read_frame(&mut server, 1 << 20).await.unwrap(),
+            vec![7; 10_000]
+        );
+        writer.await.unwrap();
+
+        let e = read_frame(&mut server, 1 << 20).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_max_frame_size() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let e = write_frame(&mut client, &[0; 9], 8).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        write_frame(&mut client, &[0; 9], 16).await.unwrap();
+        let e = read_frame(&mut server, 8).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&10u32.to_le_bytes()).await.unwrap();
+        client.write_all(b"short").await.unwrap();
+        drop(client);
+        let e = read_frame(&mut server, 16).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
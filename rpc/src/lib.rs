@@ -49,15 +49,53 @@
 //! ```
 //!
 //! See the example `ping` at `./examples/ping` for a complete example.
+//!
+//! ## Wire protocol
+//!
+//! Requests and responses are sent as length-prefixed frames, see
+//! [`frame`], so messages of any size up to the maximum frame size of
+//! the service can be exchanged, whatever their content.
+//!
+//! A connection to each peer is kept open and shared by all the requests
+//! to it. Each message is tagged with the id of its request, see
+//! [`connection`], so many requests can be in flight on a connection,
+//! and each response is matched to its request.
+//!
+//! The connections are kept by a [`Client`], reconnecting after failures
+//! with a backoff, see [`client`]. Each request has timeouts to connect,
+//! write the request and wait for the response, failing it with
+//! [`CallError::Timeout`] when a peer is down or too slow.
 
+pub mod client;
+pub mod connection;
+pub mod error;
+pub mod frame;
+
+pub use client::{Client, Health, Timeouts};
+pub use error::{CallError, Phase};
+
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Duration;
 
 pub type Byte = u8;
 pub type Bytes = Vec<Byte>;
 pub type Result<T> = std::result::Result<T, std::io::Error>;
 
+/// Default maximum number of connections handled at the same time.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// Default maximum number of requests handled at the same time
+/// for each connection.
+pub const DEFAULT_MAX_INFLIGHT_REQUESTS: usize = 64;
+
 /// Trait for RPC request.
 pub trait RpcRequest {
     /// Serialize the request into [`Bytes`].
@@ -82,6 +120,44 @@ pub trait RpcResponse {
     fn to_string(&self) -> String;
 }
 
+/// Trait for computing the response to each request received by a
+/// [`Service`].
+///
+/// Implemented for closures taking the request and the address of the
+/// peer that sent it:
+///
+/// ``` rust
+/// use rpc::{PingRequest, PingResponse, RpcRequest};
+///
+/// let handler = |request: PingRequest, _peer| async move {
+///     PingResponse::new(format!("Pong: {}", request.to_string()))
+/// };
+/// # fn check<H: rpc::Handler<PingRequest, PingResponse>>(_: H) {}
+/// # check(handler);
+/// ```
+pub trait Handler<Req, Res>: Send + Sync + 'static {
+    /// Compute the response to a request received from `peer`.
+    fn handle(
+        &self,
+        request: Req,
+        peer: SocketAddr,
+    ) -> impl Future<Output = Res> + Send;
+}
+
+impl<Req, Res, F, Fut> Handler<Req, Res> for F
+where
+    F: Fn(Req, SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Res> + Send,
+{
+    fn handle(
+        &self,
+        request: Req,
+        peer: SocketAddr,
+    ) -> impl Future<Output = Res> + Send {
+        self(request, peer)
+    }
+}
+
 /// RPC service.
 ///
 /// Mainly used for sending requests and handling requests.
@@ -94,11 +170,23 @@ where
     /// Socket address of the service.
     socket: SocketAddr,
 
-    /// Request type.
+    /// Request sent by [`send_request()`].
+    ///
+    /// [`send_request()`]: #method.send_request
     request: Req,
 
-    /// Response type.
-    response: Res,
+    /// Client sending the requests, shared by the clones.
+    client: Client<Req, Res>,
+
+    /// Maximum size in bytes of a request or response.
+    max_frame_size: u32,
+
+    /// Maximum number of connections handled at the same time.
+    max_connections: usize,
+
+    /// Maximum number of requests handled at the same time
+    /// for each connection.
+    max_inflight_requests: usize,
 }
 
 impl<Req, Res> Service<Req, Res>
@@ -106,71 +194,271 @@ where
     Req: RpcRequest,
     Res: RpcResponse,
 {
-    /// Create a new service.
-    pub fn new(socket: SocketAddr, request: Req, response: Res) -> Self {
-        Service { socket, request, response }
-    }
-
-    /// Send a request to the service.
-    /// As sending a request is the main purpose,
-    /// log output of sending is at [`log::info!`] level,
-    /// whereas receiving is at [`log::debug!`] level.
-    pub async fn send_request(&self, target: SocketAddr) -> Result<Res> {
-        let stream = tokio::net::TcpStream::connect(target).await;
-        let mut stream = match stream {
-            Ok(stream) => stream,
-            Err(e) => {
-                log::error!("Failed to connect to {}: {}", target, e);
-                return Err(e);
-            }
-        };
-        log::trace!("Connected to {:?}", target);
-
-        stream.write_all(&self.request.serialize()).await?;
-        log::info!("Sent request [{}] to {}", self.request.to_string(), target);
-
-        let mut buffer: Bytes = vec![0; 1024];
-        let n = stream.read(&mut buffer).await?;
-        let response = Res::deserialize(buffer[..n].to_vec());
-        log::debug!(
-            "Received response [{}] from {}",
-            response.to_string(),
-            target
-        );
+    /// Create a new service,
+    /// with a maximum frame size of [`frame::DEFAULT_MAX_FRAME_SIZE`],
+    /// at most [`DEFAULT_MAX_CONNECTIONS`] connections at a time,
+    /// and at most [`DEFAULT_MAX_INFLIGHT_REQUESTS`] requests at a time
+    /// on each of them.
+    pub fn new(socket: SocketAddr, request: Req) -> Self {
+        Service {
+            socket,
+            request,
+            client: Client::new(),
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_inflight_requests: DEFAULT_MAX_INFLIGHT_REQUESTS,
+        }
+    }
+
+    /// Set the maximum size in bytes of a request or response.
+    ///
+    /// Larger messages are neither sent nor received, so that a peer
+    /// cannot make the service allocate an arbitrary amount of memory.
+    pub fn set_max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = max_frame_size;
+        self.client = self.client.set_max_frame_size(max_frame_size);
+        self
+    }
+
+    /// Get the maximum size in bytes of a request or response.
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
 
-        Ok(response)
+    /// Set the maximum number of connections handled at the same time.
+    ///
+    /// Further connections wait in the backlog of the listener until
+    /// one of them is closed. The limit is at least `1`.
+    pub fn set_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
     }
 
-    /// Handle all the requests to the service.
-    /// As handling requests is the main purpose,
-    /// log output of receiving is at [`log::info!`] level,
-    /// whereas sending is at [`log::debug!`] level.
-    pub async fn handle_request(&self) -> Result<()> {
+    /// Get the maximum number of connections handled at the same time.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Set the maximum number of requests handled at the same time
+    /// for each connection.
+    ///
+    /// Further requests are not read from the connection until one of
+    /// them is replied to. The limit is at least `1`.
+    pub fn set_max_inflight_requests(
+        mut self,
+        max_inflight_requests: usize,
+    ) -> Self {
+        self.max_inflight_requests = max_inflight_requests.max(1);
+        self
+    }
+
+    /// Get the maximum number of requests handled at the same time
+    /// for each connection.
+    pub fn max_inflight_requests(&self) -> usize {
+        self.max_inflight_requests
+    }
+
+    /// Send the request of the service to the target.
+    /// See [`call()`] for details.
+    ///
+    /// [`call()`]: #method.call
+    pub async fn send_request(
+        &self,
+        target: SocketAddr,
+    ) -> std::result::Result<Res, CallError> {
+        self.call(target, &self.request).await
+    }
+
+    /// Send a request to the target service, and wait for the response.
+    /// See [`Client::call()`] for details.
+    pub async fn call(
+        &self,
+        target: SocketAddr,
+        request: &Req,
+    ) -> std::result::Result<Res, CallError> {
+        self.client.call(target, request).await
+    }
+
+    /// Set the client sending the requests, such as to share its
+    /// connections with other services. Its own maximum frame size and
+    /// timeouts apply to the requests.
+    pub fn set_client(mut self, client: Client<Req, Res>) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Get the client sending the requests,
+    /// such as to check the health of the peers.
+    pub fn client(&self) -> &Client<Req, Res> {
+        &self.client
+    }
+
+    /// Handle all the requests to the service,
+    /// replying to each with the response computed by the handler.
+    ///
+    /// Each connection is handled by its own task, up to the maximum
+    /// number of connections, so a slow peer does not delay the others.
+    /// An error on a connection only closes that connection, this only
+    /// returns if the service fails to listen on its socket. Once a
+    /// connection is closed, the requests still being handled for it
+    /// are abandoned.
+    pub async fn handle_request<H>(&self, handler: H) -> Result<()>
+    where
+        Req: Send + 'static,
+        Res: Send + 'static,
+        H: Handler<Req, Res>,
+    {
+        let handler = Arc::new(handler);
+        let permits = Arc::new(Semaphore::new(self.max_connections));
         let listener: tokio::net::TcpListener =
             tokio::net::TcpListener::bind(self.socket).await?;
         log::trace!("Listening on {:?}", self.socket);
 
         loop {
-            let (mut stream, addr) = listener.accept().await?;
+            // The semaphore is never closed.
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Such as too many open files, wait for some to close.
+                    log::error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
             log::trace!("Accepted connection from {:?}", addr);
 
-            let mut buffer: Bytes = vec![0; 1024];
-            let n = stream.read(&mut buffer).await?;
-            let request = Req::deserialize(buffer[..n].to_vec());
-            log::info!(
-                "Received request [{}] from {}",
-                request.to_string(),
-                addr
-            );
+            let handler = handler.clone();
+            let max_frame_size = self.max_frame_size;
+            let max_inflight_requests = self.max_inflight_requests;
+            tokio::spawn(async move {
+                let result = serve_connection(
+                    stream,
+                    addr,
+                    handler,
+                    max_frame_size,
+                    max_inflight_requests,
+                )
+                .await;
+                if let Err(e) = result {
+                    log::error!("Connection from {} failed: {}", addr, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Delay before accepting connections again after a failure.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
-            let response_msg = self.response.to_string();
-            stream.write_all(&self.response.serialize()).await?;
-            log::debug!("Sent response [{}] to {}", response_msg, addr);
+/// Reply to the requests received on the connection, until it is closed.
+///
+/// Each request is handled by its own task, and its response is sent as
+/// soon as it is ready, whatever the order of the requests.
+async fn serve_connection<Req, Res, H>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    handler: Arc<H>,
+    max_frame_size: u32,
+    max_inflight_requests: usize,
+) -> Result<()>
+where
+    Req: RpcRequest + Send + 'static,
+    Res: RpcResponse + Send + 'static,
+    H: Handler<Req, Res>,
+{
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let (responses, outbox) = mpsc::channel(max_inflight_requests);
+    let sending = write_responses(writer, outbox, max_frame_size);
+    let reading = read_requests(
+        reader,
+        addr,
+        handler,
+        responses,
+        max_frame_size,
+        max_inflight_requests,
+    );
+
+    // The responses are sent until the peer closes the connection, or
+    // the stream fails. Then the requests still being handled are
+    // aborted, as nobody waits for their responses.
+    tokio::select! {
+        result = reading => result,
+        result = sending => result,
+    }
+}
+
+/// Read the requests, and handle each in its own task,
+/// up to the maximum number of requests in flight.
+/// As handling requests is the main purpose,
+/// log output of receiving is at [`log::info!`] level,
+/// whereas sending is at [`log::debug!`] level.
+///
+/// The tasks handling the requests are aborted when this returns.
+async fn read_requests<Req, Res, H>(
+    mut reader: OwnedReadHalf,
+    addr: SocketAddr,
+    handler: Arc<H>,
+    responses: mpsc::Sender<Bytes>,
+    max_frame_size: u32,
+    max_inflight_requests: usize,
+) -> Result<()>
+where
+    Req: RpcRequest + Send + 'static,
+    Res: RpcResponse + Send + 'static,
+    H: Handler<Req, Res>,
+{
+    let mut handling = JoinSet::new();
+    loop {
+        // Stop reading until a request is replied to, so that a peer
+        // cannot queue an unbounded amount of work.
+        while handling.len() >= max_inflight_requests {
+            if let Some(Err(e)) = handling.join_next().await {
+                log::error!("Handling a request from {} failed: {}", addr, e);
+            }
         }
+
+        let data = match frame::read_frame(&mut reader, max_frame_size).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log::trace!("Connection from {} closed", addr);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let (id, request) = connection::decode_message(data)?;
+        let request = Req::deserialize(request);
+        log::info!("Received request [{}] from {}", request.to_string(), addr);
+
+        let handler = handler.clone();
+        let responses = responses.clone();
+        handling.spawn(async move {
+            let response = handler.handle(request, addr).await;
+            let response_msg = response.to_string();
+            let response =
+                connection::encode_message(id, &response.serialize());
+            if responses.send(response).await.is_ok() {
+                log::debug!("Sent response [{}] to {}", response_msg, addr);
+            }
+        });
     }
 }
 
-#[derive(Clone)]
+/// Write the responses as they are ready, until the stream fails.
+async fn write_responses(
+    mut writer: OwnedWriteHalf,
+    mut responses: mpsc::Receiver<Bytes>,
+    max_frame_size: u32,
+) -> Result<()> {
+    while let Some(response) = responses.recv().await {
+        frame::write_frame(&mut writer, &response, max_frame_size).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
 pub struct PingRequest {
     data: String,
 }
@@ -183,19 +471,12 @@ impl PingRequest {
 
 impl RpcRequest for PingRequest {
     fn serialize(&self) -> Bytes {
-        let mut data = self.data.as_bytes().to_vec();
-        data.push(b'\0'); // Add null byte at the end.
-        data
+        self.data.as_bytes().to_vec()
     }
 
     fn deserialize(data: Bytes) -> Self {
-        let mut data = data.to_vec();
-        data.pop(); // Remove the null byte at the end.
         match String::from_utf8(data) {
-            Ok(data) => {
-                let data = data.trim().to_string();
-                PingRequest { data }
-            }
+            Ok(data) => PingRequest { data },
             Err(_) => PingRequest { data: "Failed to parse data".to_string() },
         }
     }
@@ -205,7 +486,7 @@ impl RpcRequest for PingRequest {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct PingResponse {
     data: String,
 }
@@ -218,19 +499,12 @@ impl PingResponse {
 
 impl RpcResponse for PingResponse {
     fn serialize(&self) -> Bytes {
-        let mut data = self.data.as_bytes().to_vec();
-        data.push(b'\0'); // Add null byte at the end.
-        data
+        self.data.as_bytes().to_vec()
     }
 
     fn deserialize(data: Bytes) -> Self {
-        let mut data = data.to_vec();
-        data.pop(); // Remove the null byte at the end.
         match String::from_utf8(data) {
-            Ok(data) => {
-                let data = data.trim().to_string();
-                PingResponse { data }
-            }
+            Ok(data) => PingResponse { data },
             Err(_) => PingResponse { data: "Failed to parse data".to_string() },
         }
     }
@@ -241,3 +515,296 @@ impl RpcResponse for PingResponse {
 }
 
 pub type PingService = Service<PingRequest, PingResponse>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    /// Get an address of the loopback interface that is not in use.
+    async fn free_addr() -> SocketAddr {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Handle the requests to the service in a task,
+    /// once the service is listening.
+    async fn serve<H>(
+        service: &PingService,
+        handler: H,
+    ) -> JoinHandle<Result<()>>
+    where
+        H: Handler<PingRequest, PingResponse>,
+    {
+        let server = service.clone();
+        let target = server.socket;
+        let task =
+            tokio::spawn(async move { server.handle_request(handler).await });
+        for _ in 0..50 {
+            if TcpStream::connect(target).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        task
+    }
+
+    async fn echo(request: PingRequest, _: SocketAddr) -> PingResponse {
+        PingResponse::new(request.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_large_request() {
+        let data = "x".repeat(100_000);
+        let service =
+            PingService::new(free_addr().await, PingRequest::new(data.clone()));
+        let task = serve(&service, echo).await;
+
+        let response = service.send_request(service.socket).await.unwrap();
+        assert_eq!(response.to_string(), data);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_handler_per_request() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        );
+        let handler = |request: PingRequest, peer: SocketAddr| async move {
+            PingResponse::new(format!(
+                "{} from {}",
+                request.to_string(),
+                peer.ip()
+            ))
+        };
+        let task = serve(&service, handler).await;
+
+        let request = PingRequest::new("Hello".into());
+        let response = service.call(service.socket, &request).await.unwrap();
+        assert_eq!(response.to_string(), "Hello from 127.0.0.1");
+        // Each request gets its own response.
+        let response = service.send_request(service.socket).await.unwrap();
+        assert_eq!(response.to_string(), "Ping from 127.0.0.1");
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_connection() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        );
+        let task = serve(&service, echo).await;
+
+        // A peer sending only part of a request does not block the others.
+        let mut stalled = TcpStream::connect(service.socket).await.unwrap();
+        stalled.write_all(&[4, 0]).await.unwrap();
+        let response = service.send_request(service.socket);
+        let response = timeout(Duration::from_secs(1), response).await;
+        assert_eq!(response.unwrap().unwrap().to_string(), "Ping");
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_error_keeps_serving() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        )
+        .set_max_frame_size(16);
+
+        let task = serve(&service, echo).await;
+        let mut invalid = TcpStream::connect(service.socket).await.unwrap();
+        invalid.write_all(&1000u32.to_le_bytes()).await.unwrap();
+        drop(invalid);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let response = service.send_request(service.socket).await.unwrap();
+        assert_eq!(response.to_string(), "Ping");
+        assert!(!task.is_finished());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        )
+        .set_max_connections(1);
+        assert_eq!(service.max_connections(), 1);
+        let task = serve(&service, echo).await;
+
+        // The only connection is taken, so the request waits.
+        let stalled = TcpStream::connect(service.socket).await.unwrap();
+        let response = service.send_request(service.socket);
+        let response = timeout(Duration::from_millis(200), response).await;
+        assert!(response.is_err());
+
+        // Until the connection is closed.
+        drop(stalled);
+        let response = service.send_request(service.socket);
+        let response = timeout(Duration::from_secs(1), response).await;
+        assert_eq!(response.unwrap().unwrap().to_string(), "Ping");
+        task.abort();
+    }
+
+    /// Reply to `Hang` requests never, and to the others at once.
+    async fn hang(request: PingRequest, _: SocketAddr) -> PingResponse {
+        if request.to_string() == "Hang" {
+            std::future::pending::<()>().await;
+        }
+        PingResponse::new(request.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_aborts_requests() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        )
+        .set_max_connections(1);
+        let task = serve(&service, hang).await;
+
+        // The peer gives up on a request that is never replied to.
+        let timeouts = Timeouts {
+            response: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+        let client: Client<PingRequest, PingResponse> =
+            Client::new().set_timeouts(timeouts);
+        let request = PingRequest::new("Hang".into());
+        let e = client.call(service.socket, &request).await.unwrap_err();
+        assert!(e.is_timeout());
+
+        // Closing its connection frees it for another peer.
+        drop(client);
+        let response = service.send_request(service.socket);
+        let response = timeout(Duration::from_secs(1), response).await;
+        assert_eq!(response.unwrap().unwrap().to_string(), "Ping");
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_inflight_requests() {
+        let timeouts = Timeouts {
+            response: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        )
+        .set_client(Client::new().set_timeouts(timeouts))
+        .set_max_inflight_requests(1);
+        assert_eq!(service.max_inflight_requests(), 1);
+        let task = serve(&service, hang).await;
+
+        let response = service.send_request(service.socket).await.unwrap();
+        assert_eq!(response.to_string(), "Ping");
+
+        // The request being handled takes the only slot of the
+        // connection, so the next one is not read.
+        let request = PingRequest::new("Hang".into());
+        let (hung, waiting) = tokio::join!(
+            service.call(service.socket, &request),
+            service.send_request(service.socket),
+        );
+        assert!(hung.unwrap_err().is_timeout());
+        assert!(waiting.unwrap_err().is_timeout());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connection() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        );
+        let handler = |_: PingRequest, peer: SocketAddr| async move {
+            PingResponse::new(peer.to_string())
+        };
+        let task = serve(&service, handler).await;
+
+        // Both requests come from the same socket.
+        let first = service.send_request(service.socket).await.unwrap();
+        let second = service.clone().send_request(service.socket).await;
+        assert_eq!(first.to_string(), second.unwrap().to_string());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        );
+        let handler = |request: PingRequest, _| async move {
+            let delay = request.to_string().parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            PingResponse::new(request.to_string())
+        };
+        let task = serve(&service, handler).await;
+
+        // The responses come in the reverse order of the requests.
+        let slow = PingRequest::new("200".into());
+        let fast = PingRequest::new("10".into());
+        let start = tokio::time::Instant::now();
+        let (slow, fast) = tokio::join!(
+            service.call(service.socket, &slow),
+            service.call(service.socket, &fast),
+        );
+        assert_eq!(slow.unwrap().to_string(), "200");
+        assert_eq!(fast.unwrap().to_string(), "10");
+        assert!(start.elapsed() < Duration::from_millis(400));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout() {
+        let timeouts = Timeouts {
+            response: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+        let client = Client::new().set_timeouts(timeouts);
+        let service = PingService::new(
+            free_addr().await,
+            PingRequest::new("Ping".into()),
+        )
+        .set_client(client);
+        let handler = |request: PingRequest, _| async move {
+            match request.to_string().as_str() {
+                "Hang" => std::future::pending().await,
+                "Slow" => tokio::time::sleep(Duration::from_millis(100)).await,
+                _ => {}
+            }
+            PingResponse::new(request.to_string())
+        };
+        let task = serve(&service, handler).await;
+
+        let hang = PingRequest::new("Hang".into());
+        let e = service.call(service.socket, &hang).await.unwrap_err();
+        assert!(e.is_timeout());
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(e, CallError::Timeout { phase: Phase::Response, .. }));
+
+        // The connection is still usable.
+        let response = service.send_request(service.socket).await.unwrap();
+        assert_eq!(response.to_string(), "Ping");
+        assert_eq!(service.client().health(service.socket), Health::Connected);
+
+        // A longer timeout can be given for a request.
+        let timeouts =
+            Timeouts { response: Duration::from_secs(1), ..timeouts };
+        let slow = PingRequest::new("Slow".into());
+        let client = service.client();
+        let response =
+            client.call_with_timeouts(service.socket, &slow, timeouts);
+        assert_eq!(response.await.unwrap().to_string(), "Slow");
+        task.abort();
+    }
+}
@@ -42,11 +42,13 @@ async fn main() {
     let service = rpc::Service::new(
         listen_socket,
         rpc::PingRequest::new("Ping".to_string()),
-        rpc::PingResponse::new("Pong".to_string()),
     );
+    let handler = |_: rpc::PingRequest, _| async {
+        rpc::PingResponse::new("Pong".to_string())
+    };
 
     let srv = service.clone();
-    let task = tokio::spawn(async move { srv.handle_request().await });
+    let task = tokio::spawn(async move { srv.handle_request(handler).await });
 
     let wait_time = 10;
     log::info!("Waiting for {wait_time} seconds to send pings to other nodes");